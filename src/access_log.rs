@@ -0,0 +1,336 @@
+use crate::config::{AccessLogDefinition, AccessLogFormat};
+use crate::metrics::{Metrics, RequestRecord};
+use chrono::{DateTime, Utc};
+use futures_util::stream::StreamExt;
+use hyper::body::HttpBody;
+use hyper::header::{REFERER, USER_AGENT};
+use hyper::{Body, Request, Response};
+use log::error;
+use rustls::ProtocolVersion;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Backend which answered a request, stored in the response extensions
+#[derive(Clone, Copy, Debug)]
+pub struct UpstreamInfo {
+    pub addr: SocketAddr,
+    pub latency: Duration,
+}
+
+enum Output {
+    Stdout,
+    File { path: String, file: Mutex<File> },
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    output: Output,
+}
+
+fn open(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl AccessLog {
+    // Writes to stdout if no path or '-' is configured
+    pub fn from_config(config: &AccessLogDefinition) -> io::Result<Self> {
+        let output = match config.path.as_deref() {
+            None | Some("-") => Output::Stdout,
+            Some(path) => Output::File {
+                path: path.to_owned(),
+                file: Mutex::new(open(path)?),
+            },
+        };
+        Ok(Self {
+            format: config.format.unwrap_or_default(),
+            output,
+        })
+    }
+
+    // Opens the file again after it was moved away by logrotate
+    pub fn reopen(&self) -> io::Result<()> {
+        if let Output::File { path, file } = &self.output {
+            *file.lock().unwrap() = open(path)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, entry: &Entry) {
+        let mut line = match self.format {
+            AccessLogFormat::Common => entry.common(),
+            AccessLogFormat::Combined => entry.combined(),
+            AccessLogFormat::Json => entry.json(),
+        };
+        line.push('\n');
+        let result = match &self.output {
+            Output::Stdout => io::stdout().write_all(line.as_bytes()),
+            Output::File { file, .. } => file.lock().unwrap().write_all(line.as_bytes()),
+        };
+        if let Err(err) = result {
+            error!("Could not write access log! {}", err);
+        }
+    }
+}
+
+// Details of the connection a request was received on
+#[derive(Clone, Copy, Debug)]
+pub struct Connection {
+    pub client_ip: IpAddr,
+    pub tls_version: Option<ProtocolVersion>,
+}
+
+pub struct Entry {
+    time: DateTime<Utc>,
+    start: Instant,
+    connection: Connection,
+    method: String,
+    host: String,
+    path: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    status: u16,
+    bytes_in: Arc<AtomicU64>,
+    bytes_out: u64,
+    upstream: Option<UpstreamInfo>,
+    route: Option<String>,
+    latency: Duration,
+}
+
+fn header(request: &Request<Body>, name: hyper::header::HeaderName) -> Option<String> {
+    let value = request.headers().get(name)?.to_str().ok()?;
+    Some(value.to_owned())
+}
+
+fn tls_version(version: Option<ProtocolVersion>) -> &'static str {
+    match version {
+        Some(ProtocolVersion::TLSv1_2) => "TLSv1.2",
+        Some(ProtocolVersion::TLSv1_3) => "TLSv1.3",
+        Some(_) => "unknown",
+        None => "-",
+    }
+}
+
+impl Entry {
+    // Starts the entry for the request and counts the bytes of its body,
+    // the body of upgrade requests has to stay untouched
+    pub fn start(request: &mut Request<Body>, connection: Connection, count_body: bool) -> Self {
+        let bytes_in = Arc::new(AtomicU64::new(0));
+        if count_body && !request.body().is_end_stream() {
+            let counter = bytes_in.clone();
+            let body = std::mem::replace(request.body_mut(), Body::empty()).map(move |chunk| {
+                if let Ok(chunk) = &chunk {
+                    counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                }
+                chunk
+            });
+            *request.body_mut() = Body::wrap_stream(body);
+        }
+        Self {
+            time: Utc::now(),
+            start: Instant::now(),
+            connection,
+            method: request.method().to_string(),
+            host: crate::util::request_host(request).unwrap_or_default(),
+            path: request
+                .uri()
+                .path_and_query()
+                .map(|path| path.as_str())
+                .unwrap_or("/")
+                .to_owned(),
+            version: format!("{:?}", request.version()),
+            referer: header(request, REFERER),
+            user_agent: header(request, USER_AGENT),
+            status: 0,
+            bytes_in,
+            bytes_out: 0,
+            upstream: None,
+            route: None,
+            latency: Duration::default(),
+        }
+    }
+
+    pub fn set_route(&mut self, route: &str) {
+        self.route = Some(route.to_owned());
+    }
+
+    fn common(&self) -> String {
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {}",
+            self.connection.client_ip,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.path,
+            self.version,
+            self.status,
+            self.bytes_out
+        )
+    }
+
+    fn combined(&self) -> String {
+        format!(
+            "{} \"{}\" \"{}\"",
+            self.common(),
+            self.referer.as_deref().unwrap_or("-"),
+            self.user_agent.as_deref().unwrap_or("-")
+        )
+    }
+
+    fn json(&self) -> String {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        serde_json::json!({
+            "time": self.time.to_rfc3339(),
+            "client_ip": self.connection.client_ip.to_string(),
+            "method": self.method,
+            "host": self.host,
+            "path": self.path,
+            "protocol": self.version,
+            "status": self.status,
+            "bytes_in": self.bytes_in.load(Ordering::Relaxed),
+            "bytes_out": self.bytes_out,
+            "upstream": self.upstream.map(|upstream| upstream.addr.to_string()),
+            "upstream_latency_ms": self.upstream.map(|upstream| millis(upstream.latency)),
+            "latency_ms": millis(self.latency),
+            "tls_version": tls_version(self.connection.tls_version),
+            "route": self.route,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+        })
+        .to_string()
+    }
+}
+
+// Records the request once the response body is sent or the client went
+// away
+struct Pending {
+    log: Option<Arc<AccessLog>>,
+    metrics: Arc<Metrics>,
+    entry: Entry,
+}
+
+impl Pending {
+    fn sent(&mut self, bytes: usize) {
+        self.entry.bytes_out += bytes as u64;
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let entry = &mut self.entry;
+        entry.latency = entry.start.elapsed();
+        self.metrics.record(&RequestRecord {
+            route: entry.route.as_deref().unwrap_or(""),
+            method: &entry.method,
+            status: entry.status,
+            bytes_in: entry.bytes_in.load(Ordering::Relaxed),
+            bytes_out: entry.bytes_out,
+            upstream: entry
+                .upstream
+                .map(|upstream| (upstream.addr.to_string(), upstream.latency)),
+            latency: entry.latency,
+        });
+        if let Some(log) = &self.log {
+            log.write(entry);
+        }
+    }
+}
+
+pub fn track(
+    response: Response<Body>,
+    log: Option<Arc<AccessLog>>,
+    metrics: Arc<Metrics>,
+    mut entry: Entry,
+) -> Response<Body> {
+    entry.status = response.status().as_u16();
+    entry.upstream = response.extensions().get::<UpstreamInfo>().copied();
+    let (parts, body) = response.into_parts();
+    let mut pending = Pending {
+        log,
+        metrics,
+        entry,
+    };
+    let body = body.map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            pending.sent(chunk.len());
+        }
+        chunk
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{track, AccessLog, Connection, Entry, UpstreamInfo};
+    use crate::config::{AccessLogDefinition, AccessLogFormat};
+    use hyper::{Body, Request, Response};
+    use rustls::ProtocolVersion;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn connection() -> Connection {
+        Connection {
+            client_ip: "192.168.0.1".parse().unwrap(),
+            tls_version: Some(ProtocolVersion::TLSv1_3),
+        }
+    }
+
+    fn request() -> Request<Body> {
+        Request::post("/api?q=1")
+            .header("host", "a.test")
+            .header("user-agent", "curl")
+            .body(Body::from("request"))
+            .unwrap()
+    }
+
+    #[test]
+    fn formats() {
+        let mut entry = Entry::start(&mut request(), connection(), false);
+        entry.status = 200;
+        entry.bytes_out = 42;
+        let common = entry.common();
+        assert!(common.starts_with("192.168.0.1 - - ["));
+        assert!(common.ends_with("] \"POST /api?q=1 HTTP/1.1\" 200 42"));
+        assert!(entry.combined().ends_with(" 200 42 \"-\" \"curl\""));
+    }
+
+    #[tokio::test]
+    async fn log_file() {
+        let path = std::env::temp_dir().join(format!("heimdall-access-{}.log", std::process::id()));
+        let log = Arc::new(
+            AccessLog::from_config(&AccessLogDefinition {
+                path: Some(path.to_string_lossy().into_owned()),
+                format: Some(AccessLogFormat::Json),
+            })
+            .unwrap(),
+        );
+        let mut request = request();
+        let mut entry = Entry::start(&mut request, connection(), true);
+        entry.set_route("a.test/api");
+        hyper::body::to_bytes(request.into_body()).await.unwrap();
+
+        let mut response = Response::new(Body::from("response"));
+        response.extensions_mut().insert(UpstreamInfo {
+            addr: "127.0.0.1:8000".parse().unwrap(),
+            latency: Duration::from_millis(5),
+        });
+        let response = track(response, Some(log), Default::default(), entry);
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        let line = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(json["client_ip"], "192.168.0.1");
+        assert_eq!(json["host"], "a.test");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["bytes_in"], 7);
+        assert_eq!(json["bytes_out"], 8);
+        assert_eq!(json["upstream"], "127.0.0.1:8000");
+        assert_eq!(json["upstream_latency_ms"], 5.0);
+        assert_eq!(json["tls_version"], "TLSv1.3");
+        assert_eq!(json["route"], "a.test/api");
+    }
+}
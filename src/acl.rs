@@ -1,3 +1,8 @@
+use crate::config::{ConfigError, IpAclDefinition};
+use ipnet::IpNet;
+use log::error;
+use std::net::IpAddr;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum AllowedMethods {
     Any,
@@ -13,42 +18,153 @@ impl AllowedMethods {
     }
 }
 
-pub fn parse_allowed_methods(allowed_methods: Vec<String>) -> AllowedMethods {
+// Methods which can safely be sent again after a failed attempt
+pub fn idempotent_methods() -> AllowedMethods {
+    AllowedMethods::Only(vec![
+        hyper::Method::GET,
+        hyper::Method::HEAD,
+        hyper::Method::OPTIONS,
+        hyper::Method::PUT,
+        hyper::Method::DELETE,
+        hyper::Method::TRACE,
+    ])
+}
+
+pub fn parse_allowed_methods(allowed_methods: Vec<String>) -> Result<AllowedMethods, ConfigError> {
     if allowed_methods.is_empty() {
-        AllowedMethods::Any
-    } else {
-        AllowedMethods::Only(
-            allowed_methods
-                .into_iter()
-                .map(|s| match s.to_lowercase().as_str() {
-                    "options" => hyper::Method::OPTIONS,
-                    "get" => hyper::Method::GET,
-                    "post" => hyper::Method::POST,
-                    "put" => hyper::Method::PUT,
-                    "delete" => hyper::Method::DELETE,
-                    "head" => hyper::Method::HEAD,
-                    "trace" => hyper::Method::TRACE,
-                    "connect" => hyper::Method::CONNECT,
-                    "patch" => hyper::Method::PATCH,
-                    _ => panic!("Invalid http method '{}' for route!", s),
+        return Ok(AllowedMethods::Any);
+    }
+    let methods = allowed_methods
+        .into_iter()
+        .map(|s| match s.to_lowercase().as_str() {
+            "options" => Ok(hyper::Method::OPTIONS),
+            "get" => Ok(hyper::Method::GET),
+            "post" => Ok(hyper::Method::POST),
+            "put" => Ok(hyper::Method::PUT),
+            "delete" => Ok(hyper::Method::DELETE),
+            "head" => Ok(hyper::Method::HEAD),
+            "trace" => Ok(hyper::Method::TRACE),
+            "connect" => Ok(hyper::Method::CONNECT),
+            "patch" => Ok(hyper::Method::PATCH),
+            _ => Err(ConfigError::Invalid {
+                reason: format!("Invalid http method '{}'", s),
+            }),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(AllowedMethods::Only(methods))
+}
+
+// Accepts networks ('10.0.0.0/8') and single addresses ('10.0.0.1')
+pub fn parse_network(entry: &str) -> Option<IpNet> {
+    entry
+        .parse::<IpNet>()
+        .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+}
+
+// Clients on dual stack listeners have IPv4-mapped addresses, they are
+// matched against the IPv4 networks
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpAcl {
+    // Any client if not set
+    allow: Option<Vec<IpNet>>,
+    deny: Vec<IpNet>,
+}
+
+impl IpAcl {
+    // An invalid entry denies all clients, ignoring it could allow
+    // clients which are meant to be denied
+    pub fn from_config(definition: &IpAclDefinition) -> Self {
+        let parse = |entries: &[String]| -> Option<Vec<IpNet>> {
+            entries
+                .iter()
+                .map(|entry| {
+                    let network = parse_network(entry);
+                    if network.is_none() {
+                        error!(
+                            "Invalid address '{}' in ip_acl, denying all clients!",
+                            entry
+                        );
+                    }
+                    network
                 })
-                .collect(),
-        )
+                .collect()
+        };
+        match (parse(&definition.allow), parse(&definition.deny)) {
+            (Some(allow), Some(deny)) => Self {
+                allow: if allow.is_empty() { None } else { Some(allow) },
+                deny,
+            },
+            _ => Self {
+                allow: Some(vec![]),
+                deny: vec![],
+            },
+        }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = canonical_ip(ip);
+        if self.deny.iter().any(|network| network.contains(&ip)) {
+            return false;
+        }
+        match &self.allow {
+            Some(allow) => allow.iter().any(|network| network.contains(&ip)),
+            None => true,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::parse_allowed_methods;
-    use super::AllowedMethods;
+    use super::{AllowedMethods, IpAcl};
+    use crate::config::IpAclDefinition;
     use hyper::Method;
+
+    fn acl(allow: &[&str], deny: &[&str]) -> IpAcl {
+        IpAcl::from_config(&IpAclDefinition {
+            allow: allow.iter().map(|entry| entry.to_string()).collect(),
+            deny: deny.iter().map(|entry| entry.to_string()).collect(),
+        })
+    }
+
+    #[test]
+    fn ip_acl() {
+        let vpn = acl(
+            &["10.8.0.0/16", "fd00::/8", "192.168.1.10"],
+            &["10.8.1.0/24"],
+        );
+        assert!(vpn.allows("10.8.0.5".parse().unwrap()));
+        assert!(vpn.allows("192.168.1.10".parse().unwrap()));
+        assert!(vpn.allows("fd12::1".parse().unwrap()));
+        assert!(vpn.allows("::ffff:10.8.2.1".parse().unwrap()));
+        assert!(!vpn.allows("10.8.1.5".parse().unwrap()));
+        assert!(!vpn.allows("192.168.1.11".parse().unwrap()));
+        assert!(!vpn.allows("2001:db8::1".parse().unwrap()));
+
+        let blocked = acl(&[], &["203.0.113.0/24", "2001:db8::/32"]);
+        assert!(blocked.allows("198.51.100.1".parse().unwrap()));
+        assert!(!blocked.allows("203.0.113.7".parse().unwrap()));
+        assert!(!blocked.allows("2001:db8::1".parse().unwrap()));
+
+        assert!(acl(&[], &[]).allows("198.51.100.1".parse().unwrap()));
+        let invalid = acl(&[], &["10.0.0.0/33"]);
+        assert!(!invalid.allows("198.51.100.1".parse().unwrap()));
+    }
     #[test]
     fn valid_acl() {
-        let allowed = parse_allowed_methods(vec!["GET".to_owned()]);
-        assert_eq!(allowed.contains(&Method::GET), true);
-        assert_eq!(allowed.contains(&Method::PATCH), false);
+        let allowed = parse_allowed_methods(vec!["GET".to_owned()]).unwrap();
+        assert!(allowed.contains(&Method::GET));
+        assert!(!allowed.contains(&Method::PATCH));
 
-        assert_eq!(parse_allowed_methods(vec![]), AllowedMethods::Any);
+        assert_eq!(parse_allowed_methods(vec![]).unwrap(), AllowedMethods::Any);
         let all_methods = vec![
             Method::OPTIONS,
             Method::GET,
@@ -70,7 +186,9 @@ mod tests {
             "trace".to_owned(),
             "Connect".to_owned(),
             "patch".to_owned(),
-        ]);
+        ])
+        .unwrap();
         assert_eq!(AllowedMethods::Only(all_methods), methods);
+        assert!(parse_allowed_methods(vec!["GET".to_owned(), "FETCH".to_owned()]).is_err());
     }
 }
@@ -0,0 +1,777 @@
+#![allow(non_local_definitions)]
+use crate::config::{AcmeChallenge, AcmeDefinition, CertificateDefinition, Config};
+use crate::state::State;
+use crate::timeout;
+use crate::tls;
+use failure::Fail;
+use hyper::client::connect::Connect;
+use hyper::client::HttpConnector;
+use hyper::header::{CONTENT_TYPE, LOCATION};
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use log::{error, info, warn};
+use rcgen::{
+    Certificate, CertificateParams, CustomExtension, DistinguishedName, DnType, KeyPair, SanType,
+    PKCS_ECDSA_P256_SHA256,
+};
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::sign::{self, CertifiedKey};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::time::delay_for;
+
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const DEFAULT_RENEW_BEFORE_DAYS: u64 = 30;
+const DEFAULT_CHECK_INTERVAL_SECS: u64 = 12 * 60 * 60;
+const RETRY_INTERVAL_SECS: u64 = 60 * 60;
+const POLL_ATTEMPTS: usize = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+// Per request to the ACME server, including reading the response body
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Pending challenge responses, answered directly from memory
+#[derive(Default)]
+pub struct Challenges {
+    // HTTP-01 key authorizations by token
+    http: RwLock<HashMap<String, String>>,
+    // TLS-ALPN-01 validation certificates by domain
+    tls_alpn: RwLock<HashMap<String, CertifiedKey>>,
+}
+
+impl Challenges {
+    pub fn http_token(&self, token: &str) -> Option<String> {
+        self.http.read().unwrap().get(token).cloned()
+    }
+
+    pub fn tls_alpn_cert(&self, domain: &str) -> Option<CertifiedKey> {
+        self.tls_alpn.read().unwrap().get(domain).cloned()
+    }
+}
+
+// All domains to obtain certificates for, these are the configured ACME
+// domains and the exact host names of all routes, except those with a
+// manually configured certificate
+pub fn domains(config: &Config) -> Vec<String> {
+    let acme = match &config.acme {
+        Some(acme) => acme,
+        None => return vec![],
+    };
+    let manual: Vec<String> = config
+        .certificates
+        .iter()
+        .flat_map(|entry| entry.domains.iter())
+        .map(|domain| domain.to_lowercase())
+        .collect();
+    let mut domains: Vec<String> = acme
+        .domains
+        .iter()
+        .chain(config.routes.iter().filter_map(|route| route.host.as_ref()))
+        .map(|domain| domain.trim_end_matches('.').to_lowercase())
+        .filter(|domain| !domain.starts_with("*.") && !manual.contains(domain))
+        .collect();
+    domains.sort();
+    domains.dedup();
+    domains
+}
+
+fn cert_file(acme: &AcmeDefinition, domain: &str) -> PathBuf {
+    Path::new(&acme.storage_dir)
+        .join(domain)
+        .join("fullchain.pem")
+}
+
+fn pkey_file(acme: &AcmeDefinition, domain: &str) -> PathBuf {
+    Path::new(&acme.storage_dir)
+        .join(domain)
+        .join("privkey.pem")
+}
+
+// Certificate entries for all previously obtained certificates
+pub fn certificate_entries(config: &Config) -> Vec<CertificateDefinition> {
+    let acme = match &config.acme {
+        Some(acme) => acme,
+        None => return vec![],
+    };
+    domains(config)
+        .into_iter()
+        .filter(|domain| cert_file(acme, domain).exists() && pkey_file(acme, domain).exists())
+        .map(|domain| CertificateDefinition {
+            cert_file: cert_file(acme, &domain).to_string_lossy().into_owned(),
+            pkey_file: pkey_file(acme, &domain).to_string_lossy().into_owned(),
+            domains: vec![domain],
+        })
+        .collect()
+}
+
+fn needs_renewal(acme: &AcmeDefinition, domain: &str) -> bool {
+    let renew_before = acme.renew_before_days.unwrap_or(DEFAULT_RENEW_BEFORE_DAYS);
+    let renew_at = SystemTime::now() + Duration::from_secs(renew_before * 24 * 60 * 60);
+    match tls::load_certs(&cert_file(acme, domain).to_string_lossy()) {
+        Ok(certs) => match certs.first().and_then(|c| tls::certificate_expiry(&c.0)) {
+            Some(expiry) => expiry < renew_at,
+            None => true,
+        },
+        Err(_) => true,
+    }
+}
+
+// Obtains and renews certificates for all domains in the background
+pub async fn run(state: Arc<State>, challenges: Arc<Challenges>) {
+    loop {
+        let config = state.config();
+        let mut interval = DEFAULT_CHECK_INTERVAL_SECS;
+        if let Some(acme) = &config.acme {
+            interval = acme.check_interval_secs.unwrap_or(interval);
+            match renew(&config, acme, &challenges).await {
+                Ok(true) => state.reload_certificates(),
+                Ok(false) => {}
+                Err(err) => {
+                    error!("Could not renew certificates! {}", err);
+                    interval = interval.min(RETRY_INTERVAL_SECS);
+                }
+            }
+        }
+        delay_for(Duration::from_secs(interval.max(1))).await;
+    }
+}
+
+// Returns true if at least one certificate was obtained
+async fn renew(
+    config: &Config,
+    acme: &AcmeDefinition,
+    challenges: &Challenges,
+) -> Result<bool, AcmeError> {
+    let due: Vec<String> = domains(config)
+        .into_iter()
+        .filter(|domain| needs_renewal(acme, domain))
+        .collect();
+    if due.is_empty() {
+        return Ok(false);
+    }
+    let mut account = Account::connect(acme).await?;
+    let mut failed = vec![];
+    for domain in &due {
+        info!("Obtaining certificate for '{}'", domain);
+        let result = account.issue(domain, acme.challenge, challenges).await;
+        challenges.http.write().unwrap().clear();
+        challenges.tls_alpn.write().unwrap().remove(domain);
+        match result.and_then(|(chain, key)| store(acme, domain, &chain, &key)) {
+            Ok(()) => info!("Obtained certificate for '{}'", domain),
+            Err(err) => {
+                error!("Could not obtain certificate for '{}'! {}", domain, err);
+                failed.push(domain.clone());
+            }
+        }
+    }
+    if failed.len() == due.len() {
+        return Err(AcmeError::Acme {
+            detail: format!("no certificate obtained for {}", failed.join(", ")),
+        });
+    }
+    Ok(true)
+}
+
+// The key comes first, a new certificate must never be loaded with the
+// key of the previous one
+fn store(acme: &AcmeDefinition, domain: &str, chain: &str, key: &str) -> Result<(), AcmeError> {
+    fs::create_dir_all(Path::new(&acme.storage_dir).join(domain))?;
+    write_file(&pkey_file(acme, domain), key, 0o600)?;
+    write_file(&cert_file(acme, domain), chain, 0o644)?;
+    Ok(())
+}
+
+// Replaces the file at once so that a crash or the certificate watcher
+// never sees a partially written file
+fn write_file(path: &Path, data: &str, mode: u32) -> io::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    let temp = path.with_file_name(name);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&temp)?;
+    file.write_all(data.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+fn base64url<T: AsRef<[u8]>>(data: T) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn jwk(key: &EcdsaKeyPair) -> Value {
+    // Uncompressed point, 0x04 followed by x and y
+    let point = key.public_key().as_ref();
+    json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": base64url(&point[1..33]),
+        "y": base64url(&point[33..65]),
+    })
+}
+
+// JWK thumbprint as defined in RFC 7638
+fn thumbprint(key: &EcdsaKeyPair) -> String {
+    let jwk = jwk(key);
+    let canonical = format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+        jwk["x"].as_str().unwrap(),
+        jwk["y"].as_str().unwrap()
+    );
+    base64url(digest(&SHA256, canonical.as_bytes()))
+}
+
+// Flattened JWS, an empty payload is used for POST-as-GET requests
+fn jws(
+    key: &EcdsaKeyPair,
+    rng: &SystemRandom,
+    protected: &Value,
+    payload: Option<&Value>,
+) -> Result<Value, AcmeError> {
+    let protected = base64url(protected.to_string());
+    let payload = match payload {
+        Some(payload) => base64url(payload.to_string()),
+        None => String::new(),
+    };
+    let signature = key
+        .sign(rng, format!("{}.{}", protected, payload).as_bytes())
+        .map_err(|_| AcmeError::Crypto)?;
+    Ok(json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": base64url(signature),
+    }))
+}
+
+fn https_client(acme: &AcmeDefinition) -> Result<Client<HttpsConnector<HttpConnector>>, AcmeError> {
+    let connector = match &acme.ca_file {
+        None => HttpsConnector::new(),
+        Some(ca_file) => {
+            let mut tls = rustls::ClientConfig::new();
+            let mut reader = io::BufReader::new(fs::File::open(ca_file)?);
+            if tls.root_store.add_pem_file(&mut reader).is_err() {
+                return Err(AcmeError::Acme {
+                    detail: format!("invalid CA file {}", ca_file),
+                });
+            }
+            let mut http = HttpConnector::new();
+            http.enforce_http(false);
+            HttpsConnector::from((http, tls))
+        }
+    };
+    Ok(Client::builder().build(connector))
+}
+
+struct Response {
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(&self) -> Result<Value, AcmeError> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+// Sends the request and reads the whole response within the timeout
+async fn send<C>(
+    client: &Client<C>,
+    request: Request<Body>,
+    timeout: Duration,
+) -> Result<hyper::Response<Vec<u8>>, AcmeError>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let url = request.uri().to_string();
+    let response = async {
+        let (parts, body) = client.request(request).await?.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        Ok(hyper::Response::from_parts(parts, body.to_vec()))
+    };
+    match timeout::run(Some(timeout), response).await {
+        Some(result) => result,
+        None => Err(AcmeError::Timeout { url }),
+    }
+}
+
+struct Account {
+    client: Client<HttpsConnector<HttpConnector>>,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    directory: Value,
+    nonce: Option<String>,
+    kid: Option<String>,
+}
+
+impl Account {
+    async fn connect(acme: &AcmeDefinition) -> Result<Self, AcmeError> {
+        let client = https_client(acme)?;
+        let request = Request::get(acme.directory_url.as_str()).body(Body::empty())?;
+        let response = send(&client, request, REQUEST_TIMEOUT).await?;
+        let mut account = Self {
+            client,
+            key: load_account_key(acme)?,
+            rng: SystemRandom::new(),
+            directory: serde_json::from_slice(response.body())?,
+            nonce: None,
+            kid: None,
+        };
+        let payload = json!({
+            "termsOfServiceAgreed": true,
+            "contact": acme.contact,
+        });
+        let url = account.endpoint("newAccount")?;
+        let response = account.post(&url, Some(&payload)).await?;
+        account.kid = response.location;
+        if account.kid.is_none() {
+            return Err(AcmeError::Acme {
+                detail: "no account url returned".to_owned(),
+            });
+        }
+        Ok(account)
+    }
+
+    fn endpoint(&self, name: &str) -> Result<String, AcmeError> {
+        match self.directory[name].as_str() {
+            Some(url) => Ok(url.to_owned()),
+            None => Err(AcmeError::Acme {
+                detail: format!("directory has no '{}'", name),
+            }),
+        }
+    }
+
+    async fn fetch_nonce(&mut self) -> Result<String, AcmeError> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let request = Request::builder()
+            .method(Method::HEAD)
+            .uri(self.endpoint("newNonce")?)
+            .body(Body::empty())?;
+        let response = send(&self.client, request, REQUEST_TIMEOUT).await?;
+        match response.headers().get("replay-nonce") {
+            Some(nonce) => Ok(nonce.to_str().unwrap_or_default().to_owned()),
+            None => Err(AcmeError::Acme {
+                detail: "no nonce returned".to_owned(),
+            }),
+        }
+    }
+
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response, AcmeError> {
+        let mut retried = false;
+        loop {
+            let nonce = self.fetch_nonce().await?;
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": nonce,
+                "url": url,
+            });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = jwk(&self.key),
+            }
+            let body = jws(&self.key, &self.rng, &protected, payload)?;
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(body.to_string()))?;
+            let response = send(&self.client, request, REQUEST_TIMEOUT).await?;
+            let status = response.status();
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_owned())
+            };
+            self.nonce = header("replay-nonce");
+            let location = header(LOCATION.as_str());
+            let body = response.into_body();
+            if status.is_success() {
+                return Ok(Response { location, body });
+            }
+            let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+            let kind = problem["type"].as_str().unwrap_or_default();
+            if kind == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(AcmeError::Acme {
+                detail: format!(
+                    "{} {} {}",
+                    status,
+                    kind,
+                    problem["detail"].as_str().unwrap_or_default()
+                ),
+            });
+        }
+    }
+
+    // Polls the resource until its status is not pending or processing
+    async fn poll(&mut self, url: &str) -> Result<Value, AcmeError> {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource = self.post(url, None).await?.json()?;
+            match resource["status"].as_str() {
+                Some("pending") | Some("processing") => delay_for(POLL_INTERVAL).await,
+                _ => return Ok(resource),
+            }
+        }
+        Err(AcmeError::Acme {
+            detail: format!("timeout waiting for {}", url),
+        })
+    }
+
+    // Orders a certificate for the domain, returns the PEM encoded
+    // certificate chain and private key
+    async fn issue(
+        &mut self,
+        domain: &str,
+        challenge: AcmeChallenge,
+        challenges: &Challenges,
+    ) -> Result<(String, String), AcmeError> {
+        let payload = json!({
+            "identifiers": [{ "type": "dns", "value": domain }],
+        });
+        let url = self.endpoint("newOrder")?;
+        let response = self.post(&url, Some(&payload)).await?;
+        let order_url = response.location.clone().ok_or_else(|| AcmeError::Acme {
+            detail: "no order url returned".to_owned(),
+        })?;
+        let order = response.json()?;
+
+        let authorizations: Vec<String> = order["authorizations"]
+            .as_array()
+            .map(|a| {
+                a.iter()
+                    .filter_map(|u| u.as_str())
+                    .map(|u| u.to_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        for url in authorizations {
+            self.authorize(&url, domain, challenge, challenges).await?;
+        }
+
+        let mut params = CertificateParams::new(vec![domain.to_owned()]);
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, domain);
+        let cert = Certificate::from_params(params)?;
+        let csr = cert.serialize_request_der()?;
+        let finalize = order["finalize"].as_str().unwrap_or_default().to_owned();
+        self.post(&finalize, Some(&json!({ "csr": base64url(csr) })))
+            .await?;
+
+        let order = self.poll(&order_url).await?;
+        let certificate = match (order["status"].as_str(), order["certificate"].as_str()) {
+            (Some("valid"), Some(url)) => url.to_owned(),
+            _ => {
+                return Err(AcmeError::Acme {
+                    detail: format!("order failed: {}", order),
+                })
+            }
+        };
+        let chain = self.post(&certificate, None).await?.body;
+        Ok((
+            String::from_utf8_lossy(&chain).into_owned(),
+            cert.serialize_private_key_pem(),
+        ))
+    }
+
+    async fn authorize(
+        &mut self,
+        url: &str,
+        domain: &str,
+        challenge: AcmeChallenge,
+        challenges: &Challenges,
+    ) -> Result<(), AcmeError> {
+        let authorization = self.post(url, None).await?.json()?;
+        if authorization["status"].as_str() == Some("valid") {
+            return Ok(());
+        }
+        let selected = authorization["challenges"]
+            .as_array()
+            .and_then(|c| {
+                c.iter()
+                    .find(|c| c["type"].as_str() == Some(challenge.as_str()))
+            })
+            .cloned()
+            .ok_or_else(|| AcmeError::Acme {
+                detail: format!("no {} challenge offered for {}", challenge.as_str(), domain),
+            })?;
+        let token = selected["token"].as_str().unwrap_or_default();
+        let key_authorization = format!("{}.{}", token, thumbprint(&self.key));
+        match challenge {
+            AcmeChallenge::Http01 => {
+                challenges
+                    .http
+                    .write()
+                    .unwrap()
+                    .insert(token.to_owned(), key_authorization);
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let cert = tls_alpn_certificate(domain, &key_authorization)?;
+                challenges
+                    .tls_alpn
+                    .write()
+                    .unwrap()
+                    .insert(domain.to_owned(), cert);
+            }
+        }
+
+        let challenge_url = selected["url"].as_str().unwrap_or_default().to_owned();
+        self.post(&challenge_url, Some(&json!({}))).await?;
+        let authorization = self.poll(url).await?;
+        if authorization["status"].as_str() != Some("valid") {
+            warn!("Validation of '{}' failed: {}", domain, authorization);
+            return Err(AcmeError::Acme {
+                detail: format!("validation failed for {}", domain),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn load_account_key(acme: &AcmeDefinition) -> Result<EcdsaKeyPair, AcmeError> {
+    let path = Path::new(&acme.storage_dir).join("account.pem");
+    let key = if path.exists() {
+        KeyPair::from_pem(&fs::read_to_string(&path)?)?
+    } else {
+        let key = KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?;
+        fs::create_dir_all(&acme.storage_dir)?;
+        write_file(&path, &key.serialize_pem(), 0o600)?;
+        info!("Created ACME account key '{}'", path.display());
+        key
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.serialize_der())
+        .map_err(|_| AcmeError::Crypto)
+}
+
+// Self signed certificate with the acmeIdentifier extension (RFC 8737)
+fn tls_alpn_certificate(domain: &str, key_authorization: &str) -> Result<CertifiedKey, AcmeError> {
+    let mut params = CertificateParams::new(vec![domain.to_owned()]);
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.subject_alt_names = vec![SanType::DnsName(domain.to_owned())];
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(
+        digest(&SHA256, key_authorization.as_bytes()).as_ref(),
+    )];
+    let cert = Certificate::from_params(params)?;
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let key = sign::any_supported_type(&key).map_err(|_| AcmeError::Crypto)?;
+    Ok(CertifiedKey::new(
+        vec![rustls::Certificate(cert.serialize_der()?)],
+        Arc::new(key),
+    ))
+}
+
+#[derive(Debug, Fail)]
+pub enum AcmeError {
+    #[fail(display = "Io error: {}", err)]
+    Io { err: io::Error },
+    #[fail(display = "Http error: {}", err)]
+    Http { err: hyper::Error },
+    #[fail(display = "Json error: {}", err)]
+    Json { err: serde_json::Error },
+    #[fail(display = "Certificate error: {}", err)]
+    Certificate { err: rcgen::RcgenError },
+    #[fail(display = "Crypto error")]
+    Crypto,
+    #[fail(display = "Acme error: {}", detail)]
+    Acme { detail: String },
+    #[fail(display = "Request to {} timed out", url)]
+    Timeout { url: String },
+}
+
+impl From<io::Error> for AcmeError {
+    fn from(err: io::Error) -> AcmeError {
+        AcmeError::Io { err }
+    }
+}
+
+impl From<hyper::Error> for AcmeError {
+    fn from(err: hyper::Error) -> AcmeError {
+        AcmeError::Http { err }
+    }
+}
+
+impl From<hyper::http::Error> for AcmeError {
+    fn from(err: hyper::http::Error) -> AcmeError {
+        AcmeError::Acme {
+            detail: err.to_string(),
+        }
+    }
+}
+
+impl From<hyper::http::uri::InvalidUri> for AcmeError {
+    fn from(err: hyper::http::uri::InvalidUri) -> AcmeError {
+        AcmeError::Acme {
+            detail: err.to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for AcmeError {
+    fn from(err: serde_json::Error) -> AcmeError {
+        AcmeError::Json { err }
+    }
+}
+
+impl From<rcgen::RcgenError> for AcmeError {
+    fn from(err: rcgen::RcgenError) -> AcmeError {
+        AcmeError::Certificate { err }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64url, domains, jwk, jws, send, thumbprint, write_file, AcmeError};
+    use crate::config::{AcmeChallenge, AcmeDefinition, CertificateDefinition, Config};
+    use hyper::{Body, Client, Request};
+    use ring::rand::SystemRandom;
+    use ring::signature::{
+        EcdsaKeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING,
+    };
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn key() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn signed_request() {
+        let key = key();
+        let protected = json!({ "alg": "ES256", "nonce": "abc", "url": "https://foo.bar" });
+        let body = jws(&key, &SystemRandom::new(), &protected, Some(&json!({}))).unwrap();
+        assert_eq!(body["payload"], base64url("{}"));
+        assert_eq!(body["protected"], base64url(protected.to_string()));
+
+        let message = format!(
+            "{}.{}",
+            body["protected"].as_str().unwrap(),
+            body["payload"].as_str().unwrap()
+        );
+        let signature =
+            base64::decode_config(body["signature"].as_str().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap();
+        let point = {
+            use ring::signature::KeyPair;
+            key.public_key().as_ref().to_vec()
+        };
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+            .verify(message.as_bytes(), &signature)
+            .unwrap();
+
+        let empty = jws(&key, &SystemRandom::new(), &protected, None).unwrap();
+        assert_eq!(empty["payload"], "");
+    }
+
+    #[test]
+    fn replace_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("heimdall-acme-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("privkey.pem");
+        write_file(&path, "first", 0o600).unwrap();
+        write_file(&path, "second", 0o600).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Only the file itself is left
+        let names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["privkey.pem"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn key_thumbprint() {
+        let key = key();
+        let jwk = jwk(&key);
+        assert_eq!(jwk["kty"], "EC");
+        assert_eq!(jwk["x"].as_str().unwrap().len(), 43);
+        assert_eq!(jwk["y"].as_str().unwrap().len(), 43);
+        let thumbprint = thumbprint(&key);
+        assert_eq!(thumbprint.len(), 43);
+        assert!(!thumbprint.contains('='));
+    }
+
+    #[test]
+    fn acme_domains() {
+        let mut config = Config::default();
+        assert!(domains(&config).is_empty());
+
+        config.acme = Some(AcmeDefinition {
+            directory_url: "https://localhost:14000/dir".to_owned(),
+            contact: vec![],
+            storage_dir: "acme".to_owned(),
+            domains: vec!["Example.com".to_owned(), "manual.example.com".to_owned()],
+            challenge: AcmeChallenge::Http01,
+            ca_file: None,
+            renew_before_days: None,
+            check_interval_secs: None,
+        });
+        config.routes[0].host = Some("www.example.com".to_owned());
+        config.routes[1].host = Some("*.example.com".to_owned());
+        config.certificates.push(CertificateDefinition {
+            domains: vec!["manual.example.com".to_owned()],
+            cert_file: "fullchain.pem".to_owned(),
+            pkey_file: "privkey.pem".to_owned(),
+        });
+        assert_eq!(
+            domains(&config),
+            vec!["example.com".to_owned(), "www.example.com".to_owned()]
+        );
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        // Sends the headers of the first response but never its body, later
+        // connections get no response at all
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            let mut first = true;
+            while let Ok((mut stream, _)) = listener.accept().await {
+                if first {
+                    let mut buffer = [0; 1024];
+                    let _ = stream.read(&mut buffer).await;
+                    let head = "HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\n";
+                    stream.write_all(head.as_bytes()).await.unwrap();
+                    first = false;
+                }
+                connections.push(stream);
+            }
+        });
+        let client = Client::new();
+        for _ in 0..2 {
+            let request = Request::get(format!("http://{}/directory", addr))
+                .body(Body::empty())
+                .unwrap();
+            match send(&client, request, Duration::from_millis(50)).await {
+                Err(AcmeError::Timeout { url }) => {
+                    assert_eq!(url, format!("http://{}/directory", addr))
+                }
+                other => panic!("unexpected result {:?}", other.map(|r| r.status())),
+            }
+        }
+    }
+}
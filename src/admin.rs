@@ -0,0 +1,275 @@
+use crate::router::Router;
+use crate::state::State;
+use crate::upstream::Upstream;
+use chrono::{DateTime, Utc};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::accept::{self, Accept};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+// Plain http listener for operators, it should only be reachable from
+// trusted networks
+pub async fn serve(tcp: TcpListener, addr: SocketAddr, state: Arc<State>) {
+    if !addr.ip().is_loopback() {
+        warn!(
+            "Admin listener {} is not bound to loopback, it has no authentication!",
+            addr
+        );
+    }
+    info!("Admin listener on {}", addr);
+    run(accept::from_stream(tcp), state).await;
+}
+
+// Access is controlled by the permissions of the socket file
+// Bound before the privileges are dropped, the socket directory may not be
+// writable afterwards
+pub fn bind_unix(path: &str) -> Option<UnixListener> {
+    // A socket left over from a previous run would make the bind fail
+    let _ = std::fs::remove_file(path);
+    match UnixListener::bind(path) {
+        Ok(listener) => Some(listener),
+        Err(err) => {
+            error!("Could not bind admin socket '{}'! {}", path, err);
+            None
+        }
+    }
+}
+
+pub async fn serve_unix(mut listener: UnixListener, path: String, state: Arc<State>) {
+    info!("Admin listener on '{}'", path);
+    run(accept::from_stream(listener.incoming()), state).await;
+}
+
+async fn run<I, S>(incoming: I, state: Arc<State>)
+where
+    I: Accept<Conn = S, Error = std::io::Error>,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = make_service_fn(move |_: &S| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(handle(req, &state)) }
+            }))
+        }
+    });
+    if let Err(err) = Server::builder(incoming).serve(service).await {
+        error!("Error during admin server execution! {}", err);
+    }
+}
+
+fn handle(request: Request<Body>, state: &State) -> Response<Body> {
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, METRICS_CONTENT_TYPE)
+            .body(Body::from(
+                state.metrics().render(state.router().upstreams()),
+            ))
+            .unwrap(),
+        (&Method::GET, "/routes") => json_response(routes(&state.router())),
+        (&Method::GET, "/upstreams") => json_response(upstreams(&state.router())),
+        (&Method::GET, "/certificates") => json_response(certificates(
+            &state.certificate_expiries(),
+            SystemTime::now(),
+        )),
+        (&Method::POST, "/reload") => match state.reload() {
+            Ok(()) => text_response(StatusCode::OK, "Reloaded config".to_owned()),
+            Err(err) => text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Could not reload config! {}", err),
+            ),
+        },
+        (&Method::POST, path) => match upstream_action(path) {
+            Some((addr, disabled)) if state.set_disabled(addr, disabled) => {
+                let router = state.router();
+                let upstreams = router.upstreams().iter().filter(|u| u.addr() == addr);
+                json_response(upstreams.map(|u| upstream(u)).collect())
+            }
+            Some((addr, _)) => {
+                text_response(StatusCode::NOT_FOUND, format!("Unknown upstream {}!", addr))
+            }
+            None => not_found(),
+        },
+        _ => not_found(),
+    }
+}
+
+fn json_response(value: Value) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, JSON_CONTENT_TYPE)
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+fn text_response(status: StatusCode, text: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(text))
+        .unwrap()
+}
+
+fn not_found() -> Response<Body> {
+    text_response(StatusCode::NOT_FOUND, "Not found!".to_owned())
+}
+
+// Parses '/upstreams/<addr>/disable' and '/upstreams/<addr>/enable'
+fn upstream_action(path: &str) -> Option<(SocketAddr, bool)> {
+    let rest = path.strip_prefix("/upstreams/")?;
+    let (addr, action) = rest.split_once('/')?;
+    let disabled = match action {
+        "disable" => true,
+        "enable" => false,
+        _ => return None,
+    };
+    Some((addr.parse().ok()?, disabled))
+}
+
+// Disabled upstreams are draining until their last request is done
+fn upstream_state(upstream: &Upstream) -> &'static str {
+    match (upstream.is_disabled(), upstream.stats().active()) {
+        (false, _) => "enabled",
+        (true, 0) => "disabled",
+        (true, _) => "draining",
+    }
+}
+
+fn upstream(upstream: &Upstream) -> Value {
+    let stats = upstream.stats();
+    json!({
+        "addr": upstream.addr().to_string(),
+        "state": upstream_state(upstream),
+        "active": stats.active(),
+        "requests": stats.requests(),
+        "connections": stats.connections(),
+        "circuit_breaker": upstream.breaker().map(|breaker| breaker.state()),
+    })
+}
+
+fn upstreams(router: &Router) -> Value {
+    router.upstreams().iter().map(|u| upstream(u)).collect()
+}
+
+fn routes(router: &Router) -> Value {
+    router
+        .balancers()
+        .iter()
+        .map(|balancer| {
+            let backends: Vec<Value> = balancer
+                .backends()
+                .iter()
+                .map(|backend| {
+                    json!({
+                        "addr": backend.upstream.addr().to_string(),
+                        "weight": backend.weight,
+                        "healthy": backend.health.is_healthy(),
+                        "available": backend.is_available(),
+                        "state": upstream_state(&backend.upstream),
+                    })
+                })
+                .collect();
+            json!({
+                "route": balancer.route(),
+                "balance": balancer.strategy(),
+                "health_check": balancer.health_check().is_some(),
+                "backends": backends,
+            })
+        })
+        .collect()
+}
+
+fn certificates(expiries: &[(String, Option<SystemTime>)], now: SystemTime) -> Value {
+    expiries
+        .iter()
+        .map(|(name, expiry)| {
+            let days_left = expiry.map(|expiry| match expiry.duration_since(now) {
+                Ok(left) => (left.as_secs() / 86_400) as i64,
+                Err(past) => -((past.duration().as_secs() / 86_400) as i64),
+            });
+            json!({
+                "name": name,
+                "expires": expiry.map(|expiry| DateTime::<Utc>::from(expiry).to_rfc3339()),
+                "days_left": days_left,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{certificates, routes, upstream_action, upstreams};
+    use crate::config::Config;
+    use crate::router::Router;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn actions() {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        assert_eq!(
+            upstream_action("/upstreams/127.0.0.1:8000/disable"),
+            Some((addr, true))
+        );
+        assert_eq!(
+            upstream_action("/upstreams/127.0.0.1:8000/enable"),
+            Some((addr, false))
+        );
+        assert_eq!(
+            upstream_action("/upstreams/[::1]:80/disable"),
+            Some(("[::1]:80".parse().unwrap(), true))
+        );
+        assert_eq!(upstream_action("/upstreams/127.0.0.1:8000/stop"), None);
+        assert_eq!(upstream_action("/upstreams/backend/disable"), None);
+        assert_eq!(upstream_action("/reload/127.0.0.1:8000/disable"), None);
+    }
+
+    #[test]
+    fn inspect() {
+        let router = Router::from_config(Config::default()).unwrap();
+        router.upstreams()[0].set_disabled(true);
+        let routes = routes(&router);
+        assert_eq!(routes[0]["route"], "/");
+        assert_eq!(routes[0]["balance"], "round-robin");
+        assert_eq!(routes[0]["backends"][0]["addr"], "127.0.0.1:8000");
+        assert_eq!(routes[0]["backends"][0]["healthy"], true);
+        assert_eq!(routes[0]["backends"][0]["available"], false);
+        assert_eq!(routes[1]["backends"][0]["state"], "enabled");
+
+        let upstreams = upstreams(&router);
+        assert_eq!(upstreams[0]["state"], "disabled");
+        assert_eq!(upstreams[0]["circuit_breaker"], serde_json::Value::Null);
+        assert_eq!(upstreams[1]["addr"], "127.0.0.1:7000");
+    }
+
+    #[test]
+    fn certificate_expiries() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000 * 86_400);
+        let expiries = vec![
+            (
+                "a.test".to_owned(),
+                Some(now + Duration::from_secs(30 * 86_400 + 60)),
+            ),
+            (
+                "default".to_owned(),
+                Some(now - Duration::from_secs(86_400)),
+            ),
+            ("b.test".to_owned(), None),
+        ];
+        let certificates = certificates(&expiries, now);
+        assert_eq!(certificates[0]["days_left"], 30);
+        assert_eq!(certificates[0]["expires"], "1972-10-27T00:01:00+00:00");
+        assert_eq!(certificates[1]["name"], "default");
+        assert_eq!(certificates[1]["days_left"], -1);
+        assert_eq!(certificates[2]["expires"], serde_json::Value::Null);
+    }
+}
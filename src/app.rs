@@ -3,7 +3,7 @@ use crate::config::Config;
 use clap::{App, AppSettings, Arg, SubCommand};
 
 #[cfg_attr(tarpaulin, skip)]
-pub fn run() -> Option<Config> {
+pub fn run() -> Option<(String, Config)> {
     let matches = App::new("heimdall")
         .setting(AppSettings::ArgRequiredElseHelp)
         .version(env!("CARGO_PKG_VERSION"))
@@ -35,7 +35,7 @@ pub fn run() -> Option<Config> {
 
     if let Some(matches) = matches.subcommand_matches("default") {
         let file = matches.value_of("FILE_NAME").unwrap();
-        if let Err(err) = config::write_default(&file) {
+        if let Err(err) = config::write_default(file) {
             println!("Could not write default config! {}", err);
         } else {
             println!("Wrote default config file to '{}'", file);
@@ -45,8 +45,8 @@ pub fn run() -> Option<Config> {
 
     if let Some(matches) = matches.subcommand_matches("run") {
         let file = matches.value_of("FILE_NAME").unwrap();
-        match config::load(&file) {
-            Ok(config) => return Some(config),
+        match config::load(file) {
+            Ok(config) => return Some((file.to_owned(), config)),
             Err(err) => {
                 println!("Error loading config file: '{}'! {}", &file, err);
                 return None;
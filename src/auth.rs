@@ -0,0 +1,301 @@
+use crate::config::BasicAuthDefinition;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use log::{error, warn};
+use ring::digest::{digest, SHA256};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::Semaphore;
+
+const DEFAULT_REALM: &str = "heimdall";
+// Checked credentials are remembered so that the expensive hash is not
+// computed again for every request
+const MAX_CACHED: usize = 1024;
+// Hashes computed at the same time per route, further requests wait
+const MAX_VERIFICATIONS: usize = 2;
+
+#[derive(Clone, Debug)]
+enum Hash {
+    Bcrypt(String),
+    Argon2(String),
+}
+
+impl Hash {
+    fn parse(hash: &str) -> Option<Self> {
+        if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            Some(Hash::Bcrypt(hash.to_owned()))
+        } else if hash.starts_with("$argon2") && PasswordHash::new(hash).is_ok() {
+            Some(Hash::Argon2(hash.to_owned()))
+        } else {
+            None
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Argon2(hash) => match PasswordHash::new(hash) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok(),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+// Digests of checked 'Authorization' headers, cleared once full
+#[derive(Default)]
+struct Digests(Mutex<HashSet<Vec<u8>>>);
+
+impl Digests {
+    fn contains(&self, key: &[u8]) -> bool {
+        self.0.lock().unwrap().contains(key)
+    }
+
+    fn insert(&self, key: Vec<u8>) {
+        let mut digests = self.0.lock().unwrap();
+        if digests.len() >= MAX_CACHED {
+            digests.clear();
+        }
+        digests.insert(key);
+    }
+}
+
+// Result of checking the credentials of a request without hashing
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    Allowed,
+    Rejected,
+    // The password has to be verified with 'verify'
+    Unverified,
+}
+
+// Users of an htpasswd file with bcrypt or argon2 hashes
+pub struct BasicAuth {
+    realm: String,
+    users: HashMap<String, Hash>,
+    forward_authorization: bool,
+    allowed: Digests,
+    rejected: Digests,
+    verifications: Semaphore,
+}
+
+impl BasicAuth {
+    // A file which cannot be read rejects all requests
+    pub fn from_config(definition: &BasicAuthDefinition) -> Self {
+        let users = match std::fs::read_to_string(&definition.htpasswd) {
+            Ok(data) => parse_htpasswd(&data, &definition.htpasswd),
+            Err(err) => {
+                error!(
+                    "Could not read htpasswd file '{}', rejecting all requests! {}",
+                    definition.htpasswd, err
+                );
+                HashMap::new()
+            }
+        };
+        Self {
+            realm: definition
+                .realm
+                .clone()
+                .unwrap_or_else(|| DEFAULT_REALM.to_owned()),
+            users,
+            forward_authorization: definition.forward_authorization.unwrap_or(false),
+            allowed: Digests::default(),
+            rejected: Digests::default(),
+            verifications: Semaphore::new(MAX_VERIFICATIONS),
+        }
+    }
+
+    // Whether the 'Authorization' header is sent to the targets
+    pub fn forward_authorization(&self) -> bool {
+        self.forward_authorization
+    }
+
+    // Value of the 'WWW-Authenticate' header of 401 responses
+    pub fn challenge(&self) -> HeaderValue {
+        HeaderValue::from_str(&format!(
+            "Basic realm=\"{}\", charset=\"UTF-8\"",
+            self.realm.replace('"', "")
+        ))
+        .unwrap_or_else(|_| HeaderValue::from_static("Basic"))
+    }
+
+    // Cheap enough to run on the runtime threads, only previously verified
+    // credentials are allowed
+    pub fn check(&self, headers: &HeaderMap<HeaderValue>) -> Check {
+        let value = match headers.get(AUTHORIZATION) {
+            Some(value) => value.as_bytes(),
+            None => return Check::Rejected,
+        };
+        let key = digest(&SHA256, value);
+        if self.allowed.contains(key.as_ref()) {
+            return Check::Allowed;
+        }
+        if self.rejected.contains(key.as_ref()) {
+            return Check::Rejected;
+        }
+        match credentials(value) {
+            Some((user, _)) if self.users.contains_key(&user) => Check::Unverified,
+            _ => Check::Rejected,
+        }
+    }
+
+    // Hashes the password on the blocking thread pool and remembers the
+    // result for 'check'
+    pub async fn verify(&self, headers: &HeaderMap<HeaderValue>) -> bool {
+        let value = match headers.get(AUTHORIZATION) {
+            Some(value) => value.as_bytes(),
+            None => return false,
+        };
+        let (hash, password) = match credentials(value) {
+            Some((user, password)) => match self.users.get(&user) {
+                Some(hash) => (hash.clone(), password),
+                None => return false,
+            },
+            None => return false,
+        };
+        let key = digest(&SHA256, value).as_ref().to_vec();
+        let _permit = self.verifications.acquire().await;
+        // Another request with the same credentials may have finished
+        if self.allowed.contains(&key) {
+            return true;
+        }
+        if self.rejected.contains(&key) {
+            return false;
+        }
+        let valid = tokio::task::spawn_blocking(move || hash.verify(&password))
+            .await
+            .unwrap_or(false);
+        if valid {
+            self.allowed.insert(key);
+        } else {
+            self.rejected.insert(key);
+        }
+        valid
+    }
+}
+
+// User and password of a 'Basic' authorization header
+fn credentials(value: &[u8]) -> Option<(String, String)> {
+    let value = std::str::from_utf8(value).ok()?;
+    let mut parts = value.trim().splitn(2, ' ');
+    if !parts.next()?.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64::decode(parts.next()?.trim()).ok()?).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    Some((parts.next()?.to_owned(), parts.next()?.to_owned()))
+}
+
+// Lines of 'user:hash', empty lines and comments are skipped
+fn parse_htpasswd(data: &str, file: &str) -> HashMap<String, Hash> {
+    let mut users = HashMap::new();
+    for line in data.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        let user = parts.next().unwrap_or_default();
+        match parts.next().and_then(Hash::parse) {
+            Some(hash) => {
+                users.insert(user.to_owned(), hash);
+            }
+            None => warn!(
+                "Ignoring user '{}' in '{}', only bcrypt and argon2 hashes are supported!",
+                user, file
+            ),
+        }
+    }
+    users
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{credentials, parse_htpasswd, BasicAuth, Check};
+    use crate::config::BasicAuthDefinition;
+    use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+
+    // 'secret' for both users, 'plain' is not supported
+    const HTPASSWD: &str = "
+        # Users of the internal tools
+        alice:$2b$04$wQVBk/9FhnleoqRWqQzZt.Bh76IDIhsQY006Ua4eZY6yNjQhtIRxK
+        bob:$argon2id$v=19$m=16,t=2,p=1$c2FsdHNhbHQ$865AUeURr6n8HtD+KV9954Buy8pBRcZagkPkr9xKlao
+        carol:plain
+    ";
+
+    fn authorization(user_password: &str) -> HeaderMap<HeaderValue> {
+        let mut headers = HeaderMap::new();
+        let value = format!("Basic {}", base64::encode(user_password));
+        headers.insert(AUTHORIZATION, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn htpasswd() {
+        let users = parse_htpasswd(HTPASSWD, "test");
+        assert_eq!(users.len(), 2);
+        assert!(users["alice"].verify("secret"));
+        assert!(!users["alice"].verify("wrong"));
+        assert!(users["bob"].verify("secret"));
+        assert!(!users["bob"].verify("wrong"));
+
+        assert_eq!(
+            credentials(b"Basic YWxpY2U6c2VjcmV0OjE="),
+            Some(("alice".to_owned(), "secret:1".to_owned()))
+        );
+        assert_eq!(credentials(b"Bearer YWxpY2U6c2VjcmV0"), None);
+        assert_eq!(credentials(b"Basic invalid!"), None);
+    }
+
+    #[tokio::test]
+    async fn basic_auth() {
+        let path = std::env::temp_dir().join(format!("heimdall-htpasswd-{}", std::process::id()));
+        std::fs::write(&path, HTPASSWD).unwrap();
+        let auth = BasicAuth::from_config(&BasicAuthDefinition {
+            htpasswd: path.to_str().unwrap().to_owned(),
+            realm: Some("Internal \"tools\"".to_owned()),
+            forward_authorization: None,
+        });
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            auth.challenge(),
+            "Basic realm=\"Internal tools\", charset=\"UTF-8\""
+        );
+        assert!(!auth.forward_authorization());
+        let alice = authorization("alice:secret");
+        assert_eq!(auth.check(&alice), Check::Unverified);
+        assert!(auth.verify(&alice).await);
+        assert_eq!(auth.check(&alice), Check::Allowed);
+        let bob = authorization("bob:secret");
+        assert!(auth.verify(&bob).await);
+        assert_eq!(auth.check(&bob), Check::Allowed);
+
+        // Failures are remembered as well
+        let wrong = authorization("alice:wrong");
+        assert_eq!(auth.check(&wrong), Check::Unverified);
+        assert!(!auth.verify(&wrong).await);
+        assert_eq!(auth.check(&wrong), Check::Rejected);
+
+        // Unknown users and missing headers are rejected without hashing
+        assert_eq!(auth.check(&authorization("carol:plain")), Check::Rejected);
+        assert_eq!(auth.check(&authorization("dave:secret")), Check::Rejected);
+        assert!(!auth.verify(&authorization("dave:secret")).await);
+        assert_eq!(auth.check(&HeaderMap::new()), Check::Rejected);
+
+        let missing = BasicAuth::from_config(&BasicAuthDefinition {
+            htpasswd: "/nonexistent/htpasswd".to_owned(),
+            realm: None,
+            forward_authorization: Some(true),
+        });
+        assert_eq!(
+            missing.challenge(),
+            "Basic realm=\"heimdall\", charset=\"UTF-8\""
+        );
+        assert_eq!(missing.check(&alice), Check::Rejected);
+    }
+}
@@ -0,0 +1,405 @@
+use crate::breaker::Ticket;
+use crate::config::{Balance, ConfigError};
+use crate::health::{Health, HealthCheck};
+use crate::upstream::Upstream;
+use hyper::{Body, Request};
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Backend chosen for a request, the ticket is passed back to the circuit
+// breaker together with the result
+pub struct Selected {
+    pub upstream: Arc<Upstream>,
+    pub ticket: Ticket,
+}
+
+impl Deref for Selected {
+    type Target = Upstream;
+
+    fn deref(&self) -> &Upstream {
+        &self.upstream
+    }
+}
+
+// Points per weight unit of a backend on the hash ring
+const RING_POINTS: u32 = 100;
+
+pub struct Backend {
+    pub upstream: Arc<Upstream>,
+    pub weight: u32,
+    pub health: Health,
+}
+
+impl Backend {
+    pub fn new(upstream: Arc<Upstream>, weight: u32) -> Self {
+        Self {
+            upstream,
+            weight: weight.max(1),
+            health: Health::default(),
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.health.is_healthy()
+            && !self.upstream.is_disabled()
+            && self
+                .upstream
+                .breaker()
+                .is_none_or(|breaker| breaker.is_available())
+    }
+}
+
+// Distributes the requests of a route over its backends
+pub struct Balancer {
+    route: String,
+    backends: Vec<Backend>,
+    strategy: Balance,
+    hash_header: Option<String>,
+    health_check: Option<HealthCheck>,
+    next: AtomicUsize,
+    // Current weights for the smooth weighted round robin
+    current: Mutex<Vec<i64>>,
+    // Hash ring of (point, backend index), sorted by point
+    ring: Vec<(u64, usize)>,
+}
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Balancer {
+    pub fn new(
+        route: String,
+        backends: Vec<Backend>,
+        strategy: Balance,
+        hash_header: Option<String>,
+        health_check: Option<HealthCheck>,
+    ) -> Result<Self, ConfigError> {
+        if backends.is_empty() {
+            return Err(ConfigError::Invalid {
+                reason: format!("Route '{}' needs at least one backend", route),
+            });
+        }
+        let mut ring = vec![];
+        if strategy == Balance::ConsistentHash {
+            for (index, backend) in backends.iter().enumerate() {
+                for point in 0..backend.weight * RING_POINTS {
+                    let key = format!("{}-{}", backend.upstream.addr(), point);
+                    ring.push((hash(&key), index));
+                }
+            }
+            ring.sort_unstable();
+        }
+        Ok(Self {
+            route,
+            current: Mutex::new(vec![0; backends.len()]),
+            backends,
+            strategy,
+            hash_header: hash_header.map(|h| h.to_lowercase()),
+            health_check,
+            next: AtomicUsize::new(0),
+            ring,
+        })
+    }
+
+    // Host and source of the route, used in logs
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+
+    pub fn strategy(&self) -> Balance {
+        self.strategy
+    }
+
+    pub fn backends(&self) -> &[Backend] {
+        &self.backends
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheck> {
+        self.health_check.as_ref()
+    }
+
+    // Chooses a backend, None if no backend is available. Excluded backends
+    // are only chosen if no other one is available, used to send retries to
+    // another backend
+    pub fn select(
+        &self,
+        req: &Request<Body>,
+        peer: IpAddr,
+        exclude: &[SocketAddr],
+    ) -> Option<Selected> {
+        let mut available: Vec<usize> = (0..self.backends.len())
+            .filter(|index| self.backends[*index].is_available())
+            .collect();
+        let excluded = |index: &usize| exclude.contains(&self.backends[*index].upstream.addr());
+        if available.iter().any(|index| !excluded(index)) {
+            available.retain(|index| !excluded(index));
+        }
+        loop {
+            let index = match available.len() {
+                0 => return None,
+                1 => available[0],
+                _ => match self.strategy {
+                    Balance::RoundRobin => {
+                        available[self.next.fetch_add(1, Ordering::Relaxed) % available.len()]
+                    }
+                    Balance::WeightedRoundRobin => self.weighted_round_robin(&available),
+                    Balance::LeastConnections => self.least_connections(&available),
+                    Balance::RandomTwoChoices => self.random_two_choices(&available),
+                    Balance::ConsistentHash => self.consistent_hash(req, peer, &available),
+                },
+            };
+            let upstream = &self.backends[index].upstream;
+            // Another request may have taken the trial of a half-open breaker
+            let ticket = match upstream.breaker() {
+                Some(breaker) => breaker.start(),
+                None => Some(Ticket::default()),
+            };
+            match ticket {
+                Some(ticket) => {
+                    return Some(Selected {
+                        upstream: upstream.clone(),
+                        ticket,
+                    })
+                }
+                None => available.retain(|i| *i != index),
+            }
+        }
+    }
+
+    // Shortest time until an open circuit breaker of a backend allows
+    // requests again
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.backends
+            .iter()
+            .filter_map(|backend| backend.upstream.breaker()?.retry_after())
+            .min()
+    }
+
+    // Smooth weighted round robin, spreads the picks of heavy backends
+    // instead of sending them in bursts
+    fn weighted_round_robin(&self, available: &[usize]) -> usize {
+        let total: i64 = available
+            .iter()
+            .map(|index| i64::from(self.backends[*index].weight))
+            .sum();
+        let mut current = self.current.lock().unwrap();
+        let mut best = available[0];
+        for index in available {
+            current[*index] += i64::from(self.backends[*index].weight);
+            if current[*index] > current[best] {
+                best = *index;
+            }
+        }
+        current[best] -= total;
+        best
+    }
+
+    // Whether backend a has less active requests per weight than backend b
+    fn less_loaded(&self, a: usize, b: usize) -> bool {
+        let load = |index: usize| self.backends[index].upstream.stats().active() as u64;
+        let weight = |index: usize| u64::from(self.backends[index].weight);
+        load(a) * weight(b) < load(b) * weight(a)
+    }
+
+    fn least_connections(&self, available: &[usize]) -> usize {
+        // Start at a rotating position so ties are spread evenly
+        let len = available.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut best = available[start % len];
+        for offset in 1..len {
+            let index = available[(start + offset) % len];
+            if self.less_loaded(index, best) {
+                best = index;
+            }
+        }
+        best
+    }
+
+    fn random_two_choices(&self, available: &[usize]) -> usize {
+        let len = available.len();
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0, len);
+        let second = (first + rng.gen_range(1, len)) % len;
+        let (first, second) = (available[first], available[second]);
+        if self.less_loaded(second, first) {
+            second
+        } else {
+            first
+        }
+    }
+
+    // Hashes the configured header or the client address onto the ring,
+    // unavailable backends are skipped by moving on to the next point
+    fn consistent_hash(&self, req: &Request<Body>, peer: IpAddr, available: &[usize]) -> usize {
+        let key = match self
+            .hash_header
+            .as_ref()
+            .and_then(|header| req.headers().get(header.as_str()))
+        {
+            Some(value) => hash(value.as_bytes()),
+            None => hash(&peer),
+        };
+        let start = match self.ring.binary_search_by_key(&key, |(point, _)| *point) {
+            Ok(point) | Err(point) => point,
+        };
+        (0..self.ring.len())
+            .map(|offset| self.ring[(start + offset) % self.ring.len()].1)
+            .find(|index| available.contains(index))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backend, Balancer};
+    use crate::config::Balance;
+    use crate::upstream::Upstream;
+    use hyper::{Body, Request};
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    fn balancer(weights: &[u32], strategy: Balance) -> Balancer {
+        let backends = weights
+            .iter()
+            .enumerate()
+            .map(|(index, weight)| {
+                let addr = format!("127.0.0.1:{}", 8000 + index).parse().unwrap();
+                Backend::new(
+                    Arc::new(Upstream::new(addr, &Default::default(), None, None)),
+                    *weight,
+                )
+            })
+            .collect();
+        Balancer::new(
+            "/".to_owned(),
+            backends,
+            strategy,
+            Some("X-User".to_owned()),
+            None,
+        )
+        .unwrap()
+    }
+
+    fn picks(balancer: &Balancer, count: usize) -> Vec<u16> {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        (0..count)
+            .map(|_| {
+                balancer
+                    .select(&Request::default(), peer, &[])
+                    .unwrap()
+                    .addr()
+                    .port()
+                    - 8000
+            })
+            .collect()
+    }
+
+    #[test]
+    fn without_backends() {
+        assert!(Balancer::new("/".to_owned(), vec![], Balance::RoundRobin, None, None).is_err());
+    }
+
+    #[test]
+    fn round_robin() {
+        let balancer = balancer(&[1, 5, 1], Balance::RoundRobin);
+        assert_eq!(picks(&balancer, 6), vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let balancer = balancer(&[5, 1, 1], Balance::WeightedRoundRobin);
+        assert_eq!(picks(&balancer, 7), vec![0, 0, 1, 0, 2, 0, 0]);
+    }
+
+    #[test]
+    fn least_connections() {
+        let balancer = balancer(&[1, 1], Balance::LeastConnections);
+        let mut counts = HashMap::new();
+        for pick in picks(&balancer, 10) {
+            *counts.entry(pick).or_insert(0) += 1;
+        }
+        assert_eq!(counts[&0], 5);
+        assert_eq!(counts[&1], 5);
+    }
+
+    #[test]
+    fn random_two_choices() {
+        let balancer = balancer(&[1, 1, 1], Balance::RandomTwoChoices);
+        assert!(picks(&balancer, 20).iter().all(|pick| *pick < 3));
+    }
+
+    #[test]
+    fn consistent_hash() {
+        let balancer = balancer(&[1, 1, 1], Balance::ConsistentHash);
+        let first = picks(&balancer, 1)[0];
+        assert!(picks(&balancer, 5).iter().all(|pick| *pick == first));
+
+        let by_header = |user: &str| {
+            let mut req = Request::new(Body::empty());
+            req.headers_mut().insert("x-user", user.parse().unwrap());
+            balancer
+                .select(&req, "10.0.0.1".parse().unwrap(), &[])
+                .unwrap()
+                .addr()
+        };
+        assert_eq!(by_header("alice"), by_header("alice"));
+        let spread: Vec<_> = (0..30).map(|i| by_header(&format!("user{}", i))).collect();
+        assert!(spread.iter().any(|addr| *addr != spread[0]));
+    }
+
+    #[test]
+    fn unhealthy_backends() {
+        let round_robin = balancer(&[1, 1, 1], Balance::RoundRobin);
+        round_robin.backends()[1].health.set_healthy(false);
+        assert_eq!(picks(&round_robin, 4), vec![0, 2, 0, 2]);
+
+        let hashed = balancer(&[1, 1, 1], Balance::ConsistentHash);
+        let first = picks(&hashed, 1)[0];
+        hashed.backends()[first as usize].health.set_healthy(false);
+        let second = picks(&hashed, 1)[0];
+        assert_ne!(first, second);
+        assert!(picks(&hashed, 5).iter().all(|pick| *pick == second));
+
+        for backend in hashed.backends() {
+            backend.health.set_healthy(false);
+        }
+        let peer = "10.0.0.1".parse().unwrap();
+        assert!(hashed.select(&Request::default(), peer, &[]).is_none());
+    }
+
+    #[test]
+    fn disabled_backends() {
+        let balancer = balancer(&[1, 1, 1], Balance::RoundRobin);
+        balancer.backends()[0].upstream.set_disabled(true);
+        assert_eq!(picks(&balancer, 4), vec![1, 2, 1, 2]);
+        balancer.backends()[0].upstream.set_disabled(false);
+        assert!(picks(&balancer, 3).contains(&0));
+    }
+
+    #[test]
+    fn excluded_backends() {
+        let balancer = balancer(&[1, 1], Balance::ConsistentHash);
+        let peer = "10.0.0.1".parse().unwrap();
+        let first = balancer
+            .select(&Request::default(), peer, &[])
+            .unwrap()
+            .addr();
+        let other = balancer.select(&Request::default(), peer, &[first]);
+        assert_ne!(other.unwrap().addr(), first);
+
+        balancer.backends()[1].health.set_healthy(false);
+        let only = balancer.backends()[0].upstream.addr();
+        let fallback = balancer.select(&Request::default(), peer, &[only]);
+        assert_eq!(fallback.unwrap().addr(), only);
+    }
+}
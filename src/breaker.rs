@@ -0,0 +1,330 @@
+use crate::config::CircuitBreakerDefinition;
+use log::{info, warn};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_CONSECUTIVE_FAILURES: u32 = 5;
+const DEFAULT_ERROR_RATE_PERCENT: u32 = 50;
+const DEFAULT_MIN_REQUESTS: u32 = 20;
+const DEFAULT_WINDOW_SECS: u64 = 10;
+const DEFAULT_OPEN_SECS: u64 = 30;
+
+// Result of a request as seen by the breaker
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Success,
+    // The backend answered with a 5xx status
+    ServerError,
+    // Connect errors and timeouts
+    Failure,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BreakerState {
+    Closed,
+    Open { until: Instant },
+    // A single trial request decides whether to close or open again,
+    // another one is allowed if it did not finish in time
+    HalfOpen { until: Instant },
+}
+
+// Handed out by 'start' and passed back with the result. Results of
+// requests started before the last state change are ignored, so only the
+// trial request decides about a half-open breaker.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Ticket(u64);
+
+#[derive(Clone)]
+struct Counters {
+    state: BreakerState,
+    // Changed with every state transition
+    generation: u64,
+    consecutive_failures: u32,
+    window_start: Instant,
+    requests: u32,
+    errors: u32,
+}
+
+// Stops sending requests to a failing upstream for a while
+pub struct CircuitBreaker {
+    addr: SocketAddr,
+    consecutive_failures: u32,
+    error_rate_percent: u32,
+    min_requests: u32,
+    window: Duration,
+    open: Duration,
+    counters: Mutex<Counters>,
+}
+
+impl CircuitBreaker {
+    pub fn new(addr: SocketAddr, config: &CircuitBreakerDefinition) -> Self {
+        Self {
+            addr,
+            consecutive_failures: config
+                .consecutive_failures
+                .unwrap_or(DEFAULT_CONSECUTIVE_FAILURES)
+                .max(1),
+            error_rate_percent: config
+                .error_rate_percent
+                .unwrap_or(DEFAULT_ERROR_RATE_PERCENT),
+            min_requests: config.min_requests.unwrap_or(DEFAULT_MIN_REQUESTS).max(1),
+            window: Duration::from_secs(config.window_secs.unwrap_or(DEFAULT_WINDOW_SECS).max(1)),
+            open: Duration::from_secs(config.open_secs.unwrap_or(DEFAULT_OPEN_SECS).max(1)),
+            counters: Mutex::new(Counters {
+                state: BreakerState::Closed,
+                generation: 0,
+                consecutive_failures: 0,
+                window_start: Instant::now(),
+                requests: 0,
+                errors: 0,
+            }),
+        }
+    }
+
+    // Whether a request may be sent, an open breaker allows a trial
+    // request once the open period is over
+    pub fn is_available(&self) -> bool {
+        match self.counters.lock().unwrap().state {
+            BreakerState::Closed => true,
+            BreakerState::Open { until } | BreakerState::HalfOpen { until } => {
+                Instant::now() >= until
+            }
+        }
+    }
+
+    // Name of the current state, shown by the admin API
+    pub fn state(&self) -> &'static str {
+        match self.counters.lock().unwrap().state {
+            BreakerState::Closed => "closed",
+            BreakerState::Open { .. } => "open",
+            BreakerState::HalfOpen { .. } => "half-open",
+        }
+    }
+
+    // Takes over the state of the breaker of the same upstream of the
+    // previous router, so a reload does not close an open breaker
+    pub fn restore(&self, previous: &CircuitBreaker) {
+        let counters = previous.counters.lock().unwrap().clone();
+        *self.counters.lock().unwrap() = counters;
+    }
+
+    // Called for the chosen upstream before the request is sent, None if
+    // the request must not be sent. Only one caller gets the trial request.
+    // A trial which never reports its result, e.g. because the client went
+    // away, is replaced by a new one after the open period.
+    pub fn start(&self) -> Option<Ticket> {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap();
+        match counters.state {
+            BreakerState::Closed => Some(Ticket(counters.generation)),
+            BreakerState::Open { until } | BreakerState::HalfOpen { until } if now >= until => {
+                info!("Circuit breaker of {} is half-open", self.addr);
+                counters.transition(BreakerState::HalfOpen {
+                    until: now + self.open,
+                });
+                Some(Ticket(counters.generation))
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => None,
+        }
+    }
+
+    // Time until the breaker allows requests again, None if it is not open
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self.counters.lock().unwrap().state {
+            BreakerState::Open { until } => Some(until.saturating_duration_since(Instant::now())),
+            BreakerState::HalfOpen { until } => {
+                Some(until.saturating_duration_since(Instant::now()))
+            }
+            BreakerState::Closed => None,
+        }
+    }
+
+    pub fn record(&self, ticket: Ticket, outcome: Outcome) {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap();
+        // Started before the breaker opened, closed or became half-open
+        if ticket.0 != counters.generation {
+            return;
+        }
+        match counters.state {
+            BreakerState::HalfOpen { .. } if outcome == Outcome::Success => {
+                info!("Circuit breaker of {} is closed", self.addr);
+                counters.transition(BreakerState::Closed);
+                counters.consecutive_failures = 0;
+                counters.window_start = now;
+                counters.requests = 0;
+                counters.errors = 0;
+            }
+            BreakerState::HalfOpen { .. } => {
+                warn!(
+                    "Trial request to {} failed, circuit breaker stays open",
+                    self.addr
+                );
+                counters.transition(BreakerState::Open {
+                    until: now + self.open,
+                });
+            }
+            BreakerState::Open { .. } => {}
+            BreakerState::Closed => {
+                if now.duration_since(counters.window_start) >= self.window {
+                    counters.window_start = now;
+                    counters.requests = 0;
+                    counters.errors = 0;
+                }
+                counters.requests += 1;
+                if outcome != Outcome::Success {
+                    counters.errors += 1;
+                }
+                if outcome == Outcome::Failure {
+                    counters.consecutive_failures += 1;
+                } else {
+                    counters.consecutive_failures = 0;
+                }
+                let failures = counters.consecutive_failures >= self.consecutive_failures;
+                let error_rate = counters.requests >= self.min_requests
+                    && counters.errors * 100 >= self.error_rate_percent * counters.requests;
+                if failures || error_rate {
+                    warn!(
+                        "Circuit breaker of {} is open for {:?}! {} of {} requests failed",
+                        self.addr, self.open, counters.errors, counters.requests
+                    );
+                    counters.transition(BreakerState::Open {
+                        until: now + self.open,
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl Counters {
+    fn transition(&mut self, state: BreakerState) {
+        self.state = state;
+        self.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BreakerState, CircuitBreaker, Outcome};
+    use crate::config::CircuitBreakerDefinition;
+    use std::time::Instant;
+
+    fn breaker(min_requests: u32) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "127.0.0.1:8000".parse().unwrap(),
+            &CircuitBreakerDefinition {
+                consecutive_failures: Some(3),
+                error_rate_percent: Some(50),
+                min_requests: Some(min_requests),
+                window_secs: Some(60),
+                open_secs: Some(60),
+            },
+        )
+    }
+
+    // Sends a request and records its outcome
+    fn request(breaker: &CircuitBreaker, outcome: Outcome) {
+        let ticket = breaker.start().unwrap();
+        breaker.record(ticket, outcome);
+    }
+
+    // Pretends the open period is over
+    fn expire(breaker: &CircuitBreaker) {
+        let mut counters = breaker.counters.lock().unwrap();
+        counters.state = match counters.state {
+            BreakerState::Open { .. } => BreakerState::Open {
+                until: Instant::now(),
+            },
+            BreakerState::HalfOpen { .. } => BreakerState::HalfOpen {
+                until: Instant::now(),
+            },
+            BreakerState::Closed => BreakerState::Closed,
+        };
+    }
+
+    #[test]
+    fn consecutive_failures() {
+        let breaker = breaker(100);
+        request(&breaker, Outcome::Failure);
+        request(&breaker, Outcome::Failure);
+        request(&breaker, Outcome::Success);
+        request(&breaker, Outcome::Failure);
+        request(&breaker, Outcome::Failure);
+        assert!(breaker.is_available());
+        assert_eq!(breaker.retry_after(), None);
+        request(&breaker, Outcome::Failure);
+        assert!(!breaker.is_available());
+        assert!(breaker.retry_after().unwrap().as_secs() > 50);
+    }
+
+    #[test]
+    fn error_rate() {
+        let breaker = breaker(4);
+        request(&breaker, Outcome::ServerError);
+        request(&breaker, Outcome::ServerError);
+        request(&breaker, Outcome::ServerError);
+        assert!(breaker.is_available());
+        request(&breaker, Outcome::Success);
+        assert!(!breaker.is_available());
+    }
+
+    #[test]
+    fn half_open() {
+        let breaker = CircuitBreaker::new(
+            "127.0.0.1:8000".parse().unwrap(),
+            &CircuitBreakerDefinition {
+                consecutive_failures: Some(1),
+                ..Default::default()
+            },
+        );
+        let late = breaker.start().unwrap();
+        request(&breaker, Outcome::Failure);
+        assert!(!breaker.is_available());
+        expire(&breaker);
+        assert!(breaker.is_available());
+        // Only one of the concurrent requests becomes the trial
+        let trial = breaker.start().unwrap();
+        assert_eq!(breaker.start(), None);
+        assert!(!breaker.is_available());
+        // A success of a request started before the breaker opened does not
+        // close it, only the trial decides
+        breaker.record(late, Outcome::Success);
+        assert_eq!(breaker.state(), "half-open");
+        breaker.record(trial, Outcome::Failure);
+        assert_eq!(breaker.state(), "open");
+        assert_eq!(breaker.start(), None);
+
+        // A trial which never finished is replaced after the open period
+        expire(&breaker);
+        let stuck = breaker.start().unwrap();
+        expire(&breaker);
+        assert!(breaker.is_available());
+        let trial = breaker.start().unwrap();
+        assert_eq!(breaker.start(), None);
+        breaker.record(stuck, Outcome::Failure);
+        assert_eq!(breaker.state(), "half-open");
+        breaker.record(trial, Outcome::Success);
+        assert!(breaker.is_available());
+        assert_eq!(breaker.retry_after(), None);
+
+        // Failures from before the breaker closed do not count anymore
+        for _ in 0..3 {
+            breaker.record(trial, Outcome::Failure);
+        }
+        assert_eq!(breaker.state(), "closed");
+    }
+
+    #[test]
+    fn restore() {
+        let previous = breaker(100);
+        for _ in 0..3 {
+            request(&previous, Outcome::Failure);
+        }
+        let breaker = breaker(100);
+        breaker.restore(&previous);
+        assert_eq!(breaker.state(), "open");
+        assert!(!breaker.is_available());
+    }
+}
@@ -1,49 +1,355 @@
+#![allow(non_local_definitions)]
 use failure::Fail;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::io;
 use std::net::SocketAddr;
-use toml;
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct RouteDefinition {
+    pub host: Option<String>,
     pub source: String,
-    pub target: SocketAddr,
+    pub target: TargetDefinition,
     pub target_path: Option<String>,
     pub allowed_methods: Vec<String>,
+    pub balance: Option<Balance>,
+    pub hash_header: Option<String>,
+    // Allows WebSocket and other protocol upgrades
+    pub upgrade: Option<bool>,
+    pub health_check: Option<HealthCheckDefinition>,
+    pub timeouts: Option<RouteTimeoutsDefinition>,
+    pub retry: Option<RetryDefinition>,
+    pub request_headers: Option<HeaderRulesDefinition>,
+    pub response_headers: Option<HeaderRulesDefinition>,
+    pub security_headers: Option<SecurityHeadersDefinition>,
+    pub ip_acl: Option<IpAclDefinition>,
+    pub basic_auth: Option<BasicAuthDefinition>,
+}
+
+// Either a single address or a list of backends
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum TargetDefinition {
+    Single(SocketAddr),
+    Multiple(Vec<BackendDefinition>),
+}
+
+impl TargetDefinition {
+    // Addresses and weights of all backends
+    pub fn backends(&self) -> Vec<(SocketAddr, u32)> {
+        match self {
+            TargetDefinition::Single(addr) => vec![(*addr, 1)],
+            TargetDefinition::Multiple(backends) => backends
+                .iter()
+                .map(|backend| match backend {
+                    BackendDefinition::Address(addr) => (*addr, 1),
+                    BackendDefinition::Weighted { addr, weight } => (*addr, weight.unwrap_or(1)),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum BackendDefinition {
+    Address(SocketAddr),
+    Weighted {
+        addr: SocketAddr,
+        weight: Option<u32>,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct HealthCheckDefinition {
+    pub path: String,
+    pub expected_status: Option<u16>,
+    pub interval_secs: Option<u64>,
+    pub timeout_secs: Option<u64>,
+    pub rise: Option<u32>,
+    pub fall: Option<u32>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    WeightedRoundRobin,
+    LeastConnections,
+    RandomTwoChoices,
+    ConsistentHash,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct Config {
-    pub listen: SocketAddr,
+pub struct CertificateDefinition {
+    pub domains: Vec<String>,
     pub cert_file: String,
     pub pkey_file: String,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub enum AcmeChallenge {
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+impl AcmeChallenge {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AcmeDefinition {
+    pub directory_url: String,
+    #[serde(default)]
+    pub contact: Vec<String>,
+    pub storage_dir: String,
+    #[serde(default)]
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    pub ca_file: Option<String>,
+    pub renew_before_days: Option<u64>,
+    pub check_interval_secs: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct PoolDefinition {
+    // Requests sent to a target at the same time, further ones wait
+    #[serde(alias = "size")]
+    pub max_in_flight: Option<usize>,
+    pub idle_timeout_secs: Option<u64>,
+    pub max_idle_per_host: Option<usize>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct CircuitBreakerDefinition {
+    pub consecutive_failures: Option<u32>,
+    pub error_rate_percent: Option<u32>,
+    pub min_requests: Option<u32>,
+    pub window_secs: Option<u64>,
+    pub open_secs: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RetryDefinition {
+    pub attempts: Option<u32>,
+    pub on_connect_error: Option<bool>,
+    #[serde(default)]
+    pub on_status: Vec<u16>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub backoff_ms: Option<u64>,
+    pub max_backoff_ms: Option<u64>,
+    pub max_body_bytes: Option<usize>,
+}
+
+// Header values may contain variables like '${client_ip}'
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct HeaderRulesDefinition {
+    #[serde(default)]
+    pub remove: Vec<String>,
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    #[serde(default)]
+    pub add: BTreeMap<String, String>,
+}
+
+// An empty value disables a header
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct SecurityHeadersDefinition {
+    pub hsts: Option<bool>,
+    pub hsts_max_age_secs: Option<u64>,
+    pub hsts_include_subdomains: Option<bool>,
+    pub hsts_preload: Option<bool>,
+    pub content_type_options: Option<String>,
+    pub frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub content_security_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+}
+
+// Addresses or networks of clients, denied clients are rejected even if
+// they are allowed
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct IpAclDefinition {
+    // Any client is allowed if empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct BasicAuthDefinition {
+    // File with lines of 'user:hash', read again on reloads
+    pub htpasswd: String,
+    pub realm: Option<String>,
+    // Sends the 'Authorization' header on to the targets
+    pub forward_authorization: Option<bool>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Common,
+    #[default]
+    Combined,
+    Json,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AccessLogDefinition {
+    // Standard output if not set or '-'
+    pub path: Option<String>,
+    pub format: Option<AccessLogFormat>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerRole {
+    // TLS listener for the routes
+    #[default]
+    Proxy,
+    // Plain http listener for redirects to https and ACME challenges
+    Redirect,
+    // Plain http listener for the admin API
+    Admin,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ListenerDefinition {
+    pub addr: SocketAddr,
+    pub role: Option<ListenerRole>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AdminDefinition {
+    pub listen: Option<SocketAddr>,
+    // Path of a Unix socket, used instead of or in addition to 'listen'
+    pub socket: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct TimeoutsDefinition {
+    pub handshake_secs: Option<u64>,
+    pub header_read_secs: Option<u64>,
+    pub idle_secs: Option<u64>,
+    pub upstream_connect_secs: Option<u64>,
+    pub upstream_response_secs: Option<u64>,
+    pub upgrade_idle_secs: Option<u64>,
+    pub shutdown_grace_secs: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RouteTimeoutsDefinition {
+    pub upstream_connect_secs: Option<u64>,
+    pub upstream_response_secs: Option<u64>,
+    pub upgrade_idle_secs: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Config {
+    pub listen: Option<SocketAddr>,
+    #[serde(default)]
+    pub listeners: Vec<ListenerDefinition>,
+    pub cert_file: Option<String>,
+    pub pkey_file: Option<String>,
+    pub default_certificate: Option<String>,
+    pub cert_check_interval_secs: Option<u64>,
     pub redirect_to_https: bool,
     pub acme_web_root: Option<String>,
+    // Addresses or networks of proxies in front of heimdall
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    #[serde(default)]
+    pub certificates: Vec<CertificateDefinition>,
+    pub acme: Option<AcmeDefinition>,
+    pub pool: Option<PoolDefinition>,
+    pub circuit_breaker: Option<CircuitBreakerDefinition>,
+    pub timeouts: Option<TimeoutsDefinition>,
+    pub security_headers: Option<SecurityHeadersDefinition>,
+    pub ip_acl: Option<IpAclDefinition>,
+    pub access_log: Option<AccessLogDefinition>,
+    pub admin: Option<AdminDefinition>,
+    // Unprivileged user and group to switch to after binding the sockets
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub chroot: Option<String>,
     pub routes: Vec<RouteDefinition>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         let methods: Vec<String> = vec!["GET".to_owned(), "POST".to_owned()];
-        let mut routes: Vec<RouteDefinition> = Vec::new();
-        routes.push(RouteDefinition {
-            source: "/".to_string(),
-            target: "127.0.0.1:8000".parse().unwrap(),
-            target_path: None,
-            allowed_methods: vec![],
-        });
-        routes.push(RouteDefinition {
-            source: "/stuff".to_string(),
-            target: "127.0.0.1:7000".parse().unwrap(),
-            target_path: None,
-            allowed_methods: methods,
-        });
+        let routes: Vec<RouteDefinition> = vec![
+            RouteDefinition {
+                host: None,
+                source: "/".to_string(),
+                target: TargetDefinition::Single("127.0.0.1:8000".parse().unwrap()),
+                target_path: None,
+                allowed_methods: vec![],
+                balance: None,
+                hash_header: None,
+                upgrade: None,
+                health_check: None,
+                timeouts: None,
+                retry: None,
+                request_headers: None,
+                response_headers: None,
+                security_headers: None,
+                ip_acl: None,
+                basic_auth: None,
+            },
+            RouteDefinition {
+                host: None,
+                source: "/stuff".to_string(),
+                target: TargetDefinition::Single("127.0.0.1:7000".parse().unwrap()),
+                target_path: None,
+                allowed_methods: methods,
+                balance: None,
+                hash_header: None,
+                upgrade: None,
+                health_check: None,
+                timeouts: None,
+                retry: None,
+                request_headers: None,
+                response_headers: None,
+                security_headers: None,
+                ip_acl: None,
+                basic_auth: None,
+            },
+        ];
         Self {
-            listen: "0.0.0.0:443".parse().unwrap(),
-            cert_file: "fullchain.pem".to_owned(),
-            pkey_file: "privkey.pem".to_owned(),
+            listen: Some("0.0.0.0:443".parse().unwrap()),
+            listeners: vec![],
+            cert_file: Some("fullchain.pem".to_owned()),
+            pkey_file: Some("privkey.pem".to_owned()),
+            default_certificate: None,
+            cert_check_interval_secs: None,
             redirect_to_https: false,
             acme_web_root: None,
+            trusted_proxies: vec![],
+            certificates: vec![],
+            acme: None,
+            pool: None,
+            circuit_breaker: None,
+            timeouts: None,
+            security_headers: None,
+            ip_acl: None,
+            access_log: None,
+            admin: None,
+            user: None,
+            group: None,
+            chroot: None,
             routes,
         }
     }
@@ -69,6 +375,10 @@ pub enum ConfigError {
     TomlDe { err: toml::de::Error },
     #[fail(display = "Toml error: {:?}", err)]
     TomlSer { err: toml::ser::Error },
+    #[fail(display = "Invalid config: {}", reason)]
+    Invalid { reason: String },
+    #[fail(display = "The config cannot be reloaded inside a chroot, restart heimdall instead")]
+    Chrooted,
 }
 
 impl From<io::Error> for ConfigError {
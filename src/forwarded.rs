@@ -0,0 +1,265 @@
+use crate::acl::{canonical_ip, parse_network};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, FORWARDED};
+use ipnet::IpNet;
+use log::warn;
+use std::net::IpAddr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PORT: &str = "x-forwarded-port";
+
+// Clients whose forwarding headers are kept, all other clients could
+// pretend to forward requests for somebody else
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    // Accepts networks ('10.0.0.0/8') and single addresses ('10.0.0.1')
+    pub fn from_config(entries: &[String]) -> Self {
+        let networks = entries
+            .iter()
+            .filter_map(|entry| {
+                let network = parse_network(entry);
+                if network.is_none() {
+                    warn!("Ignoring invalid trusted proxy '{}'!", entry);
+                }
+                network
+            })
+            .collect();
+        TrustedProxies(networks)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical_ip(ip);
+        self.0.iter().any(|network| network.contains(&ip))
+    }
+
+    // Address of the client, for trusted proxies the last address in
+    // 'X-Forwarded-For' which was not added by a trusted proxy
+    pub fn client_ip(&self, peer_ip: IpAddr, headers: &HeaderMap<HeaderValue>) -> IpAddr {
+        let forwarded: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        let mut ip = peer_ip;
+        for entry in forwarded.into_iter().rev() {
+            if !self.contains(ip) {
+                break;
+            }
+            match entry.trim().parse() {
+                Ok(forwarded_ip) => ip = forwarded_ip,
+                Err(_) => break,
+            }
+        }
+        ip
+    }
+}
+
+// Client connection a request was received on
+#[derive(Clone, Copy, Debug)]
+pub struct Peer {
+    pub ip: IpAddr,
+    // Address of the client behind trusted proxies, otherwise 'ip'
+    pub client_ip: IpAddr,
+    pub local_port: u16,
+    // Whether the forwarding headers sent by the client are kept
+    pub trusted: bool,
+}
+
+impl Peer {
+    // IPv4 clients of dual stack listeners are reported with their IPv4
+    // address, in the forwarding headers as well as to the ACLs
+    pub fn new(
+        ip: IpAddr,
+        local_port: u16,
+        trusted_proxies: &TrustedProxies,
+        headers: &HeaderMap<HeaderValue>,
+    ) -> Self {
+        let ip = canonical_ip(ip);
+        Self {
+            ip,
+            client_ip: canonical_ip(trusted_proxies.client_ip(ip, headers)),
+            local_port,
+            trusted: trusted_proxies.contains(ip),
+        }
+    }
+}
+
+// Sets the forwarding headers for a request of the peer. Headers of
+// trusted proxies are extended, the ones of other clients are replaced.
+pub fn apply(headers: &mut HeaderMap<HeaderValue>, peer: &Peer, host: Option<HeaderValue>) {
+    if !peer.trusted {
+        headers.remove(X_FORWARDED_FOR);
+        headers.remove(X_FORWARDED_PROTO);
+        headers.remove(X_FORWARDED_HOST);
+        headers.remove(X_FORWARDED_PORT);
+        headers.remove(FORWARDED);
+    }
+
+    append(
+        headers,
+        HeaderName::from_static(X_FORWARDED_FOR),
+        peer.ip.to_string(),
+    );
+    headers
+        .entry(X_FORWARDED_PROTO)
+        .or_insert_with(|| HeaderValue::from_static("https"));
+    if let Some(host) = &host {
+        headers
+            .entry(X_FORWARDED_HOST)
+            .or_insert_with(|| host.clone());
+    }
+    headers
+        .entry(X_FORWARDED_PORT)
+        .or_insert_with(|| HeaderValue::from(peer.local_port));
+
+    // RFC 7239, IPv6 addresses and hosts with a port have to be quoted
+    let mut element = match peer.ip {
+        IpAddr::V4(ip) => format!("for={}", ip),
+        IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+    };
+    if let Some(host) = host.as_ref().and_then(|host| host.to_str().ok()) {
+        element.push_str(&format!(";host=\"{}\"", host.replace('"', "")));
+    }
+    element.push_str(";proto=https");
+    append(headers, FORWARDED, element);
+}
+
+// Adds the value to the comma separated list of the header
+fn append(headers: &mut HeaderMap<HeaderValue>, name: HeaderName, value: String) {
+    let existing: Vec<&str> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    let value = if existing.is_empty() {
+        value
+    } else {
+        format!("{}, {}", existing.join(", "), value)
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, Peer, TrustedProxies};
+    use hyper::header::{HeaderMap, HeaderValue};
+
+    fn peer(ip: &str, trusted: bool) -> Peer {
+        Peer {
+            ip: ip.parse().unwrap(),
+            client_ip: ip.parse().unwrap(),
+            local_port: 443,
+            trusted,
+        }
+    }
+
+    fn client_headers() -> HeaderMap<HeaderValue> {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.1".parse().unwrap());
+        headers.insert("x-forwarded-proto", "http".parse().unwrap());
+        headers.insert("x-forwarded-host", "other.test".parse().unwrap());
+        headers.insert("forwarded", "for=10.0.0.1".parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn ipv4_mapped_peer() {
+        let trusted = TrustedProxies::from_config(&["10.0.0.0/8".to_owned()]);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "::ffff:198.51.100.1".parse().unwrap());
+        let peer = Peer::new("::ffff:10.0.0.2".parse().unwrap(), 443, &trusted, &headers);
+        assert_eq!(peer.ip, "10.0.0.2".parse::<std::net::IpAddr>().unwrap());
+        assert_eq!(
+            peer.client_ip,
+            "198.51.100.1".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert!(peer.trusted);
+
+        let peer = Peer::new(
+            "::ffff:198.51.100.2".parse().unwrap(),
+            443,
+            &trusted,
+            &headers,
+        );
+        assert!(!peer.trusted);
+        let mut headers = HeaderMap::new();
+        apply(&mut headers, &peer, None);
+        assert_eq!(headers["x-forwarded-for"], "198.51.100.2");
+        assert_eq!(headers["forwarded"], "for=198.51.100.2;proto=https");
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let trusted = TrustedProxies::from_config(&[
+            "10.0.0.0/8".to_owned(),
+            "::1".to_owned(),
+            "invalid".to_owned(),
+        ]);
+        assert!(trusted.contains("10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains("::1".parse().unwrap()));
+        assert!(!trusted.contains("192.168.0.1".parse().unwrap()));
+        assert!(trusted.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!trusted.contains("::ffff:192.168.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn client_ip() {
+        let trusted = TrustedProxies::from_config(&["10.0.0.0/8".to_owned()]);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "198.51.100.1, 10.0.0.2".parse().unwrap());
+        headers.append("x-forwarded-for", "10.0.0.3".parse().unwrap());
+        let ip = |peer: &str, headers: &HeaderMap<HeaderValue>| {
+            trusted
+                .client_ip(peer.parse().unwrap(), headers)
+                .to_string()
+        };
+        assert_eq!(ip("10.0.0.4", &headers), "198.51.100.1");
+        assert_eq!(ip("192.168.0.1", &headers), "192.168.0.1");
+        assert_eq!(ip("10.0.0.4", &HeaderMap::new()), "10.0.0.4");
+
+        // Entries in front of an untrusted one may be forged
+        headers.insert(
+            "x-forwarded-for",
+            "10.9.9.9, 203.0.113.5, 10.0.0.2".parse().unwrap(),
+        );
+        assert_eq!(ip("10.0.0.4", &headers), "203.0.113.5");
+        headers.insert("x-forwarded-for", "invalid".parse().unwrap());
+        assert_eq!(ip("10.0.0.4", &headers), "10.0.0.4");
+    }
+
+    #[test]
+    fn untrusted_client() {
+        let mut headers = client_headers();
+        let host = Some(HeaderValue::from_static("a.test"));
+        apply(&mut headers, &peer("192.168.0.1", false), host);
+        assert_eq!(headers["x-forwarded-for"], "192.168.0.1");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-host"], "a.test");
+        assert_eq!(headers["x-forwarded-port"], "443");
+        assert_eq!(
+            headers["forwarded"],
+            "for=192.168.0.1;host=\"a.test\";proto=https"
+        );
+    }
+
+    #[test]
+    fn trusted_proxy() {
+        let mut headers = client_headers();
+        headers.append("x-forwarded-for", "10.0.0.2".parse().unwrap());
+        let host = Some(HeaderValue::from_static("a.test:8443"));
+        apply(&mut headers, &peer("::1", true), host);
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1, 10.0.0.2, ::1");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "other.test");
+        assert_eq!(
+            headers["forwarded"],
+            "for=10.0.0.1, for=\"[::1]\";host=\"a.test:8443\";proto=https"
+        );
+    }
+}
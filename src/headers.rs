@@ -0,0 +1,234 @@
+use crate::config::HeaderRulesDefinition;
+use crate::forwarded::Peer;
+use crate::util::request_host;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Request};
+use log::warn;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+// Values which can be used in header rules as '${name}'
+pub struct Variables {
+    pub client_ip: IpAddr,
+    pub host: String,
+    pub route: String,
+    pub request_id: String,
+}
+
+impl Variables {
+    // The request id sent by a trusted proxy is kept if it is valid,
+    // otherwise a new one is used
+    pub fn new(request: &Request<Body>, peer: &Peer, route: &str) -> Self {
+        let request_id = request
+            .headers()
+            .get("x-request-id")
+            .filter(|_| peer.trusted)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| is_valid_request_id(id))
+            .map(str::to_owned)
+            .unwrap_or_else(request_id);
+        Self {
+            client_ip: peer.client_ip,
+            host: request_host(request).unwrap_or_default(),
+            route: route.to_owned(),
+            request_id,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "client_ip" => Some(self.client_ip.to_string()),
+            "host" => Some(self.host.clone()),
+            "route" => Some(self.route.clone()),
+            "request_id" => Some(self.request_id.clone()),
+            _ => None,
+        }
+    }
+
+    // Replaces the known variables, unknown ones are kept as they are
+    fn expand(&self, template: &str) -> String {
+        let mut result = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find("${") {
+            result.push_str(&rest[..start]);
+            rest = &rest[start..];
+            match rest.find('}') {
+                Some(end) => {
+                    match self.get(&rest[2..end]) {
+                        Some(value) => result.push_str(&value),
+                        None => result.push_str(&rest[..=end]),
+                    }
+                    rest = &rest[end + 1..];
+                }
+                None => break,
+            }
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+// Id to correlate the logs of heimdall and the backends
+fn request_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+// Ids end up in logs and headers of the backends, so only short ids
+// without separators or control characters are accepted
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+// Headers to remove, replace or add, applied in this order
+pub struct HeaderRules {
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, String)>,
+    add: Vec<(HeaderName, String)>,
+}
+
+fn header_name(name: &str) -> Option<HeaderName> {
+    match HeaderName::from_bytes(name.as_bytes()) {
+        Ok(name) => Some(name),
+        Err(_) => {
+            warn!("Ignoring invalid header name '{}'!", name);
+            None
+        }
+    }
+}
+
+fn header_values(values: &BTreeMap<String, String>) -> Vec<(HeaderName, String)> {
+    values
+        .iter()
+        .filter_map(|(name, value)| Some((header_name(name)?, value.clone())))
+        .collect()
+}
+
+impl HeaderRules {
+    pub fn from_config(config: &HeaderRulesDefinition) -> Self {
+        Self {
+            remove: config
+                .remove
+                .iter()
+                .filter_map(|name| header_name(name))
+                .collect(),
+            set: header_values(&config.set),
+            add: header_values(&config.add),
+        }
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap<HeaderValue>, variables: &Variables) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            if let Some(value) = header_value(name, value, variables) {
+                headers.insert(name.clone(), value);
+            }
+        }
+        for (name, value) in &self.add {
+            if let Some(value) = header_value(name, value, variables) {
+                headers.append(name.clone(), value);
+            }
+        }
+    }
+}
+
+fn header_value(name: &HeaderName, template: &str, variables: &Variables) -> Option<HeaderValue> {
+    let value = variables.expand(template);
+    match HeaderValue::from_str(&value) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("Invalid value '{}' for header {}!", value, name);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HeaderRules, Variables};
+    use crate::config::HeaderRulesDefinition;
+    use crate::forwarded::Peer;
+    use hyper::header::{HeaderMap, HeaderValue};
+    use hyper::{Body, Request};
+
+    fn variables() -> Variables {
+        Variables {
+            client_ip: "192.168.0.1".parse().unwrap(),
+            host: "a.test".to_owned(),
+            route: "a.test/api".to_owned(),
+            request_id: "42".to_owned(),
+        }
+    }
+
+    #[test]
+    fn expand_variables() {
+        let variables = variables();
+        assert_eq!(variables.expand("plain"), "plain");
+        assert_eq!(
+            variables.expand("${client_ip} via ${route} (${request_id})"),
+            "192.168.0.1 via a.test/api (42)"
+        );
+        assert_eq!(variables.expand("${unknown}${host}"), "${unknown}a.test");
+        assert_eq!(variables.expand("open ${host"), "open ${host");
+    }
+
+    #[test]
+    fn request_id() {
+        let peer = |trusted: bool| Peer {
+            ip: "10.0.0.2".parse().unwrap(),
+            client_ip: "192.168.0.1".parse().unwrap(),
+            local_port: 443,
+            trusted,
+        };
+        let id = |value: &str, trusted: bool| {
+            let request = Request::get("/")
+                .header("x-request-id", value)
+                .body(Body::empty())
+                .unwrap();
+            Variables::new(&request, &peer(trusted), "a.test/").request_id
+        };
+        assert_eq!(id("abc-123_4.5:6", true), "abc-123_4.5:6");
+        assert_ne!(id("abc-123", false), "abc-123");
+        assert_eq!(id("abc-123", false).len(), 32);
+        assert_ne!(id("a b", true), "a b");
+        assert_ne!(id("a,b", true), "a,b");
+        assert_eq!(id(&"a".repeat(128), true), "a".repeat(128));
+        assert_ne!(id(&"a".repeat(129), true), "a".repeat(129));
+
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let variables = Variables::new(&request, &peer(true), "a.test/");
+        assert_eq!(variables.client_ip.to_string(), "192.168.0.1");
+        assert_eq!(variables.request_id.len(), 32);
+    }
+
+    #[test]
+    fn apply_rules() {
+        let definition: HeaderRulesDefinition = toml::from_str(
+            r#"
+            remove = ["Server", "invalid name"]
+            [set]
+            Cache-Control = "no-store"
+            [add]
+            Via = "heimdall ${request_id}"
+            "#,
+        )
+        .unwrap();
+        let rules = HeaderRules::from_config(&definition);
+        let mut headers = HeaderMap::new();
+        headers.insert("server", HeaderValue::from_static("backend"));
+        headers.insert("cache-control", HeaderValue::from_static("max-age=60"));
+        headers.insert("via", HeaderValue::from_static("1.1 other"));
+        rules.apply(&mut headers, &variables());
+        assert!(!headers.contains_key("server"));
+        assert_eq!(headers["cache-control"], "no-store");
+        let via: Vec<_> = headers.get_all("via").iter().collect();
+        assert_eq!(via, vec!["1.1 other", "heimdall 42"]);
+    }
+}
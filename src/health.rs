@@ -0,0 +1,199 @@
+use crate::balancer::{Backend, Balancer};
+use crate::config::HealthCheckDefinition;
+use futures_util::future::join_all;
+use hyper::StatusCode;
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+const DEFAULT_INTERVAL_SECS: u64 = 10;
+const DEFAULT_TIMEOUT_SECS: u64 = 2;
+const DEFAULT_RISE: u32 = 2;
+const DEFAULT_FALL: u32 = 3;
+
+pub struct HealthCheck {
+    path: String,
+    expected_status: StatusCode,
+    interval: Duration,
+    timeout: Duration,
+    // Consecutive successful probes to mark a backend healthy again
+    rise: u32,
+    // Consecutive failed probes to mark a backend unhealthy
+    fall: u32,
+}
+
+impl HealthCheck {
+    pub fn from_config(config: &HealthCheckDefinition) -> Self {
+        let path = if config.path.starts_with('/') {
+            config.path.clone()
+        } else {
+            format!("/{}", config.path)
+        };
+        Self {
+            path,
+            expected_status: config
+                .expected_status
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .unwrap_or(StatusCode::OK),
+            interval: Duration::from_secs(
+                config.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS).max(1),
+            ),
+            timeout: Duration::from_secs(config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            rise: config.rise.unwrap_or(DEFAULT_RISE).max(1),
+            fall: config.fall.unwrap_or(DEFAULT_FALL).max(1),
+        }
+    }
+}
+
+// Health of a backend as seen by the active checks, backends start healthy
+pub struct Health {
+    healthy: AtomicBool,
+    successes: AtomicU32,
+    failures: AtomicU32,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            healthy: AtomicBool::new(true),
+            successes: AtomicU32::new(0),
+            failures: AtomicU32::new(0),
+        }
+    }
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    // Takes over the state of the same backend of the previous router
+    pub fn restore(&self, previous: &Health) {
+        self.healthy.store(previous.is_healthy(), Ordering::Relaxed);
+        let successes = previous.successes.load(Ordering::Relaxed);
+        self.successes.store(successes, Ordering::Relaxed);
+        let failures = previous.failures.load(Ordering::Relaxed);
+        self.failures.store(failures, Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    // Records a probe result and returns the new state on a transition
+    fn record(&self, success: bool, check: &HealthCheck) -> Option<bool> {
+        let healthy = self.is_healthy();
+        if success {
+            self.failures.store(0, Ordering::Relaxed);
+            let successes = self.successes.fetch_add(1, Ordering::Relaxed) + 1;
+            if !healthy && successes >= check.rise {
+                self.healthy.store(true, Ordering::Relaxed);
+                return Some(true);
+            }
+        } else {
+            self.successes.store(0, Ordering::Relaxed);
+            let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if healthy && failures >= check.fall {
+                self.healthy.store(false, Ordering::Relaxed);
+                return Some(false);
+            }
+        }
+        None
+    }
+}
+
+// Starts the health checks of all routes which define one
+pub fn spawn_checks(balancers: &[Arc<Balancer>]) {
+    for balancer in balancers {
+        if balancer.health_check().is_some() {
+            tokio::spawn(run(Arc::downgrade(balancer)));
+        }
+    }
+}
+
+// Probes the backends of a route until the route is dropped on reload
+async fn run(balancer: Weak<Balancer>) {
+    let interval = match balancer.upgrade() {
+        Some(balancer) => match balancer.health_check() {
+            Some(check) => check.interval,
+            None => return,
+        },
+        None => return,
+    };
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let balancer = match balancer.upgrade() {
+            Some(balancer) => balancer,
+            None => return,
+        };
+        let check = balancer.health_check().unwrap();
+        let probes = balancer
+            .backends()
+            .iter()
+            .map(|backend| probe(backend, check));
+        let results = join_all(probes).await;
+        for (backend, result) in balancer.backends().iter().zip(results) {
+            let transition = backend.health.record(result.is_ok(), check);
+            match (transition, result) {
+                (Some(true), _) => info!(
+                    "Backend {} of route {} is healthy again",
+                    backend.upstream.addr(),
+                    balancer.route()
+                ),
+                (Some(false), Err(reason)) => warn!(
+                    "Backend {} of route {} is unhealthy! {}",
+                    backend.upstream.addr(),
+                    balancer.route(),
+                    reason
+                ),
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn probe(backend: &Backend, check: &HealthCheck) -> Result<(), String> {
+    match tokio::time::timeout(check.timeout, backend.upstream.probe(&check.path)).await {
+        Err(_) => Err(format!("Timeout after {:?}", check.timeout)),
+        Ok(Err(err)) => Err(format!("{}", err)),
+        Ok(Ok(status)) if status != check.expected_status => {
+            Err(format!("Unexpected status {}", status))
+        }
+        Ok(Ok(_)) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Health, HealthCheck};
+    use crate::config::HealthCheckDefinition;
+
+    #[test]
+    fn transitions() {
+        let check = HealthCheck::from_config(&HealthCheckDefinition {
+            path: "health".to_owned(),
+            expected_status: None,
+            interval_secs: None,
+            timeout_secs: None,
+            rise: Some(2),
+            fall: Some(3),
+        });
+        assert_eq!(check.path, "/health");
+        let health = Health::default();
+        assert!(health.is_healthy());
+        assert_eq!(health.record(false, &check), None);
+        assert_eq!(health.record(false, &check), None);
+        assert_eq!(health.record(true, &check), None);
+        assert_eq!(health.record(false, &check), None);
+        assert_eq!(health.record(false, &check), None);
+        assert_eq!(health.record(false, &check), Some(false));
+        assert!(!health.is_healthy());
+        assert_eq!(health.record(false, &check), None);
+        assert_eq!(health.record(true, &check), None);
+        assert_eq!(health.record(true, &check), Some(true));
+        assert!(health.is_healthy());
+    }
+}
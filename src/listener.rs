@@ -0,0 +1,124 @@
+use crate::config::{AcmeChallenge, Config, ListenerRole};
+use std::net::SocketAddr;
+
+// Used for redirects and ACME challenges if no redirect listener is defined
+const DEFAULT_REDIRECT_ADDR: &str = "0.0.0.0:80";
+
+// Addresses to bind by role, from 'listen', the 'listeners' section and
+// the admin section
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listeners {
+    pub proxy: Vec<SocketAddr>,
+    pub redirect: Vec<SocketAddr>,
+    pub admin: Vec<SocketAddr>,
+}
+
+impl Listeners {
+    pub fn from_config(config: &Config) -> Self {
+        let with_role = |role: ListenerRole| {
+            config
+                .listeners
+                .iter()
+                .filter(move |listener| listener.role.unwrap_or_default() == role)
+                .map(|listener| listener.addr)
+        };
+        let proxy = config
+            .listen
+            .into_iter()
+            .chain(with_role(ListenerRole::Proxy));
+        let mut redirect: Vec<SocketAddr> = with_role(ListenerRole::Redirect).collect();
+        if redirect.is_empty() && needs_redirect(config) {
+            redirect.push(DEFAULT_REDIRECT_ADDR.parse().unwrap());
+        }
+        let admin = config
+            .admin
+            .as_ref()
+            .and_then(|admin| admin.listen)
+            .into_iter()
+            .chain(with_role(ListenerRole::Admin));
+        Self {
+            proxy: dedup(proxy),
+            redirect: dedup(redirect),
+            admin: dedup(admin),
+        }
+    }
+}
+
+// Whether plain http requests have to be answered
+fn needs_redirect(config: &Config) -> bool {
+    let http_challenge = match &config.acme {
+        Some(acme) => acme.challenge == AcmeChallenge::Http01,
+        None => false,
+    };
+    config.redirect_to_https || config.acme_web_root.is_some() || http_challenge
+}
+
+fn dedup(addrs: impl IntoIterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let mut unique = vec![];
+    for addr in addrs {
+        if !unique.contains(&addr) {
+            unique.push(addr);
+        }
+    }
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Listeners;
+    use crate::config::{AdminDefinition, Config};
+
+    #[test]
+    fn legacy_listen() {
+        let mut config = Config::default();
+        let listeners = Listeners::from_config(&config);
+        assert_eq!(listeners.proxy, vec!["0.0.0.0:443".parse().unwrap()]);
+        assert!(listeners.redirect.is_empty());
+        assert!(listeners.admin.is_empty());
+
+        config.redirect_to_https = true;
+        config.admin = Some(AdminDefinition {
+            listen: Some("127.0.0.1:9900".parse().unwrap()),
+            socket: None,
+        });
+        let listeners = Listeners::from_config(&config);
+        assert_eq!(listeners.redirect, vec!["0.0.0.0:80".parse().unwrap()]);
+        assert_eq!(listeners.admin, vec!["127.0.0.1:9900".parse().unwrap()]);
+    }
+
+    #[test]
+    fn listeners_section() {
+        let config: Config = toml::from_str(
+            r#"
+            redirect_to_https = true
+            routes = []
+
+            [[listeners]]
+            addr = '127.0.0.1:8443'
+
+            [[listeners]]
+            addr = '[::1]:8443'
+            role = 'proxy'
+
+            [[listeners]]
+            addr = '[::]:8080'
+            role = 'redirect'
+
+            [[listeners]]
+            addr = '[::1]:9900'
+            role = 'admin'
+            "#,
+        )
+        .unwrap();
+        let listeners = Listeners::from_config(&config);
+        assert_eq!(
+            listeners.proxy,
+            vec![
+                "127.0.0.1:8443".parse().unwrap(),
+                "[::1]:8443".parse().unwrap()
+            ]
+        );
+        assert_eq!(listeners.redirect, vec!["[::]:8080".parse().unwrap()]);
+        assert_eq!(listeners.admin, vec!["[::1]:9900".parse().unwrap()]);
+    }
+}
@@ -1,41 +1,172 @@
 use crate::util::{get_token, is_acme_challenge, rewrite_uri_scheme};
+use futures_util::future::{join_all, FutureExt};
 use futures_util::stream::StreamExt;
-use hyper::server::{conn::Http as HyperHttp, Builder};
+use hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::server::conn::Http as HyperHttp;
+use hyper::server::Builder;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server, StatusCode};
-use log::{error, info};
-use std::net::IpAddr;
+use log::{error, info, warn};
+use rustls::Session;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::{server::TlsStream, TlsAcceptor};
 
+mod access_log;
+use access_log::{Connection, Entry};
 mod acl;
+mod acme;
+mod admin;
+use acme::{Challenges, ACME_TLS_ALPN};
 mod app;
+mod auth;
+mod balancer;
+mod breaker;
 mod config;
+use config::Config;
+mod forwarded;
+use forwarded::Peer;
+mod headers;
+mod health;
+mod listener;
+use listener::Listeners;
+mod metrics;
+use metrics::Unmatched;
+mod privileges;
 mod proxy;
+mod retry;
 mod router;
-use router::{Router, RouterResult};
+use router::RouterResult;
+mod security;
+mod shutdown;
+use shutdown::Shutdown;
+mod state;
+use state::State;
+mod systemd;
+use systemd::{Activated, Notifier};
+mod timeout;
+use timeout::{RequestGuard, TimeoutStream, Timeouts};
 mod tls;
+mod upgrade;
+mod upstream;
 mod util;
 
+// TLS handshakes in progress per listener, further connections wait in the
+// backlog until one finishes or times out
+const MAX_HANDSHAKES: usize = 1024;
+
 async fn handle_proxy(
-    req: Request<Body>,
-    peer_ip: IpAddr,
-    router: Router,
+    mut req: Request<Body>,
+    connection: Connection,
+    local_addr: SocketAddr,
+    state: Arc<State>,
+    guard: RequestGuard,
 ) -> hyper::Result<Response<Body>> {
-    match router.eval(&req) {
-        RouterResult::Success(uri) => {
-            let req = proxy::prepare(req, peer_ip, uri).await;
-            proxy::call(req).await
-        }
-        RouterResult::NotDefined => Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::from("No route defined!"))
-            .unwrap()),
-        RouterResult::NotAllowedMethod => Ok(Response::builder()
-            .status(StatusCode::FORBIDDEN)
-            .body(Body::from("Invalid http method!"))
-            .unwrap()),
+    let router = state.router();
+    let peer = Peer::new(
+        connection.client_ip,
+        local_addr.port(),
+        router.trusted_proxies(),
+        req.headers(),
+    );
+    let client_ip = peer.client_ip;
+    let is_upgrade = upgrade::is_upgrade(&req);
+    let mut entry = Entry::start(&mut req, connection, !is_upgrade);
+    let mut result = router.eval(&req, client_ip);
+    // Credentials are verified once per request, an 'Unverified' result of
+    // the second evaluation is answered as unauthorized
+    if let RouterResult::Unverified(auth) = result {
+        result = if auth.verify(req.headers()).await {
+            router.eval(&req, client_ip)
+        } else {
+            RouterResult::Unauthorized(auth.challenge())
+        };
+    }
+    let route = match &result {
+        RouterResult::Success(forward) => Some(forward.route.clone()),
+        RouterResult::NotDefined => {
+            state.metrics().unmatched(Unmatched::NotDefined);
+            None
+        }
+        RouterResult::NotAllowedMethod => {
+            state.metrics().unmatched(Unmatched::NotAllowedMethod);
+            None
+        }
+        RouterResult::Denied => {
+            state.metrics().unmatched(Unmatched::Denied);
+            None
+        }
+        RouterResult::Unauthorized(_) | RouterResult::Unverified(_) => {
+            state.metrics().unmatched(Unmatched::Unauthorized);
+            None
+        }
+    };
+    if let Some(route) = &route {
+        entry.set_route(route.balancer.route());
+        if route.strip_authorization {
+            req.headers_mut().remove(AUTHORIZATION);
+        }
     }
+    let mut response = match result {
+        RouterResult::Success(forward) if forward.route.upgrade && is_upgrade => {
+            upgrade::forward(req, &peer, &forward, guard).await
+        }
+        RouterResult::Success(forward) => {
+            timeout::track(proxy::forward(req, &peer, &forward).await, guard)
+        }
+        RouterResult::NotDefined => timeout::track(
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("No route defined!"))
+                .unwrap(),
+            guard,
+        ),
+        RouterResult::NotAllowedMethod => timeout::track(
+            Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from("Invalid http method!"))
+                .unwrap(),
+            guard,
+        ),
+        RouterResult::Denied => timeout::track(
+            Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from("Access denied!"))
+                .unwrap(),
+            guard,
+        ),
+        RouterResult::Unverified(auth) => timeout::track(
+            Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(WWW_AUTHENTICATE, auth.challenge())
+                .body(Body::from("Authentication required!"))
+                .unwrap(),
+            guard,
+        ),
+        RouterResult::Unauthorized(challenge) => timeout::track(
+            Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(WWW_AUTHENTICATE, challenge)
+                .body(Body::from("Authentication required!"))
+                .unwrap(),
+            guard,
+        ),
+    };
+    let security = match &route {
+        Some(route) => route.security_headers.as_ref(),
+        None => router.security_headers(),
+    };
+    if let Some(security) = security {
+        security.apply(response.headers_mut());
+    }
+    let metrics = state.metrics().clone();
+    Ok(access_log::track(
+        response,
+        state.access_log(),
+        metrics,
+        entry,
+    ))
 }
 
 #[allow(clippy::unnecessary_unwrap)]
@@ -43,9 +174,15 @@ async fn handle_auxiliary(
     request: Request<Body>,
     http_redirect: bool,
     acme_web_root: Option<String>,
+    challenges: Arc<Challenges>,
 ) -> hyper::Result<Response<Body>> {
     let token = is_acme_challenge(&request);
-    if token.is_some() && acme_web_root.is_some() {
+    if let Some(key_authorization) = token.as_ref().and_then(|t| challenges.http_token(t)) {
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(key_authorization))
+            .unwrap())
+    } else if token.is_some() && acme_web_root.is_some() {
         if let Some(token) = get_token(&acme_web_root.unwrap(), &token.unwrap()) {
             Ok(Response::builder()
                 .status(StatusCode::OK)
@@ -82,89 +219,240 @@ async fn invalid_request() -> Result<Response<Body>, hyper::Error> {
     )
 }
 
+// Terminates TLS and proxies the requests of the routes, the listener is
+// closed once the graceful shutdown starts
+// Connections are dropped on errors, timeouts and after TLS-ALPN-01
+// validation, which is done with the handshake
+async fn handshake(
+    socket: std::io::Result<TcpStream>,
+    tls_acceptor: TlsAcceptor,
+    state: Arc<State>,
+) -> Option<TimeoutStream<TlsStream<TcpStream>>> {
+    let timeouts = Timeouts::from_config(state.config().timeouts.as_ref());
+    let stream = match socket {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!("Tcp handshake error! {}", err);
+            return None;
+        }
+    };
+    match timeout::run(timeouts.handshake, tls_acceptor.accept(stream)).await {
+        Some(Ok(val)) if val.get_ref().1.get_alpn_protocol() == Some(ACME_TLS_ALPN) => None,
+        Some(Ok(val)) => Some(TimeoutStream::new(val, &timeouts)),
+        Some(Err(err)) => {
+            error!("Tls handshake error! {}", err);
+            state.metrics().handshake_failed();
+            None
+        }
+        None => {
+            error!("Tls handshake timed out!");
+            state.metrics().handshake_failed();
+            None
+        }
+    }
+}
+
+async fn serve_proxy(
+    tcp: TcpListener,
+    tls_acceptor: TlsAcceptor,
+    state: Arc<State>,
+    shutdown: Shutdown,
+) {
+    let accept_state = state.clone();
+    let proxy_service = make_service_fn(move |stream: &TimeoutStream<TlsStream<TcpStream>>| {
+        let state = state.clone();
+        let (tcp, session) = stream.get_ref().get_ref();
+        // The access log shows the same address as the forwarding headers
+        let connection = Connection {
+            client_ip: acl::canonical_ip(tcp.peer_addr().unwrap().ip()),
+            tls_version: session.get_protocol_version(),
+        };
+        let local = tcp.local_addr().unwrap();
+        let activity = stream.activity().clone();
+        // The service lives as long as the connection
+        let open = state.metrics().open_connection();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                let _ = &open;
+                let guard = activity.start_request();
+                handle_proxy(req, connection, local, state.clone(), guard)
+            }))
+        }
+    });
+    // Handshakes run concurrently so slow clients do not hold up others
+    let handshakes = tcp
+        .map(|socket| handshake(socket, tls_acceptor.clone(), accept_state.clone()))
+        .buffer_unordered(MAX_HANDSHAKES)
+        .filter_map(|stream| async { stream.map(Ok::<_, hyper::Error>) });
+    let tls_server = Builder::new(
+        hyper::server::accept::from_stream(handshakes),
+        HyperHttp::new(),
+    )
+    .serve(proxy_service)
+    .with_graceful_shutdown(shutdown.wait());
+    if let Err(err) = tls_server.await {
+        error!("Error during https server execution! {}", err);
+    }
+}
+
+// Answers ACME http-01 challenges and redirects to https
+async fn serve_auxiliary(
+    tcp: TcpListener,
+    config: Arc<Config>,
+    challenges: Arc<Challenges>,
+    shutdown: Shutdown,
+) {
+    let https_upgrade = config.redirect_to_https;
+    let acme_web_root = config.acme_web_root.clone();
+    let util_service = make_service_fn(move |_| {
+        let acme_web_root = acme_web_root.clone();
+        let challenges = challenges.clone();
+        async move {
+            let acme_web_root = acme_web_root.clone();
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                handle_auxiliary(
+                    req,
+                    https_upgrade,
+                    acme_web_root.clone(),
+                    challenges.clone(),
+                )
+            }))
+        }
+    });
+    let http_server = Server::builder(hyper::server::accept::from_stream(tcp))
+        .serve(util_service)
+        .with_graceful_shutdown(shutdown.wait());
+    if let Err(err) = http_server.await {
+        error!("Error during http server execution! {}", err);
+    }
+}
+
+// Takes the sockets received from systemd or binds them
+async fn bind(addrs: &[SocketAddr], activated: &mut Activated) -> Option<Vec<TcpListener>> {
+    let mut listeners = vec![];
+    for addr in addrs {
+        let result = match activated.take(*addr) {
+            Some(listener) => TcpListener::from_std(listener),
+            None => TcpListener::bind(addr).await,
+        };
+        match result {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => {
+                error!("Could not bind socket {}! {}", addr, err);
+                return None;
+            }
+        }
+    }
+    Some(listeners)
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let config = match app::run() {
+    let (file, config) = match app::run() {
         None => return,
         Some(config) => config,
     };
-    let addr = config.listen;
-    let router = Router::from_config(config.clone());
+    let listeners = Listeners::from_config(&config);
+    if listeners.proxy.is_empty() {
+        error!("No proxy listener configured! Exting ...");
+        return;
+    }
 
-    let tls_cfg = match tls::create_config(&config) {
-        Some(cfg) => cfg,
+    let challenges = Arc::new(Challenges::default());
+    let resolver = match tls::ReloadableResolver::from_config(&config, challenges.clone()) {
+        Some(resolver) => Arc::new(resolver),
         None => {
             error!("No valid TLS config! Exting ...");
             return;
         }
     };
+    let tls_cfg = tls::create_config(&config, resolver.clone());
+
+    // All sockets are bound before anything is served, sockets from
+    // systemd are used instead of binding them again
+    let mut activated = Activated::from_env();
+    let proxies = match bind(&listeners.proxy, &mut activated).await {
+        Some(proxies) => proxies,
+        None => return,
+    };
+    let auxiliaries = match bind(&listeners.redirect, &mut activated).await {
+        Some(auxiliaries) => auxiliaries,
+        None => return,
+    };
+    let admins = match bind(&listeners.admin, &mut activated).await {
+        Some(admins) => admins,
+        None => return,
+    };
+    let admin_socket = config.admin.as_ref().and_then(|admin| admin.socket.clone());
+    let admin_unix = match &admin_socket {
+        Some(path) => match admin::bind_unix(path) {
+            Some(listener) => Some((listener, path.clone())),
+            None => return,
+        },
+        None => None,
+    };
+    activated.close_unused();
 
-    let mut tcp = match TcpListener::bind(&addr).await {
+    let shutdown = Shutdown::listen();
+    let notifier = Arc::new(Notifier::from_env());
+    let state = match State::new(file, config.clone(), resolver, notifier.clone()) {
+        Ok(state) => Arc::new(state),
         Err(err) => {
-            error!("Could not bind socket! {}", err);
+            error!("{}! Exiting ...", err);
             return;
         }
-        Ok(tcp) => tcp,
     };
-    let tls_acceptor = TlsAcceptor::from(tls_cfg);
-    let tls_incoming = tcp.incoming();
-    let proxy_service = make_service_fn(move |stream: &TlsStream<TcpStream>| {
-        let router = router.clone();
-        let peer = stream.get_ref().0.peer_addr().unwrap().ip();
-        async move {
-            Ok::<_, hyper::Error>(service_fn(move |req| {
-                handle_proxy(req, peer, router.clone())
-            }))
-        }
-    });
-    let tls_server = Builder::new(
-        hyper::server::accept::from_stream(tls_incoming.filter_map(|socket| async {
-            match socket {
-                Ok(stream) => match tls_acceptor.accept(stream).await {
-                    Ok(val) => Some(Ok::<_, hyper::Error>(val)),
-                    Err(err) => {
-                        error!("Tls handshake error! {}", err);
-                        None
-                    }
-                },
-                Err(err) => {
-                    error!("Tcp handshake error! {}", err);
-                    None
-                }
-            }
-        })),
-        HyperHttp::new(),
-    )
-    .serve(proxy_service);
-
-    let https_upgrade = config.redirect_to_https;
-    let acme_web_root = config.acme_web_root.clone();
+    // Files opened so far stay usable, everything else is accessed as the
+    // unprivileged user
+    if let Err(err) = privileges::drop_privileges(&config) {
+        error!("Could not drop privileges! {} Exiting ...", err);
+        return;
+    }
+    if config.user.is_some() && config.chroot.is_none() {
+        state.warn_unreadable();
+    }
+    tokio::spawn(state::watch(state.clone()));
+    tokio::spawn(state::report_pool_stats(state.clone()));
+    tokio::spawn(systemd::watchdog(notifier.clone()));
+    for (tcp, addr) in admins.into_iter().zip(&listeners.admin) {
+        tokio::spawn(admin::serve(tcp, *addr, state.clone()));
+    }
+    if let Some((listener, path)) = admin_unix {
+        tokio::spawn(admin::serve_unix(listener, path, state.clone()));
+    }
+    if config.acme.is_some() {
+        tokio::spawn(acme::run(state.clone(), challenges.clone()));
+    }
 
     info!("Starting up ");
 
-    if https_upgrade || acme_web_root.is_some() {
-        let util_service = make_service_fn(move |_| {
-            let acme_web_root = acme_web_root.clone();
-            async move {
-                let acme_web_root = acme_web_root.clone();
-                Ok::<_, hyper::Error>(service_fn(move |req| {
-                    handle_auxiliary(req, https_upgrade, acme_web_root.clone())
-                }))
-            }
-        });
+    let tls_acceptor = TlsAcceptor::from(tls_cfg);
+    let mut servers = vec![];
+    for (tcp, addr) in proxies.into_iter().zip(&listeners.proxy) {
+        info!("Proxy listener on {}", addr);
+        let server = serve_proxy(tcp, tls_acceptor.clone(), state.clone(), shutdown.clone());
+        servers.push(server.boxed());
+    }
+    let config = Arc::new(config);
+    for (tcp, addr) in auxiliaries.into_iter().zip(&listeners.redirect) {
+        info!("Redirect listener on {}", addr);
+        let server = serve_auxiliary(tcp, config.clone(), challenges.clone(), shutdown.clone());
+        servers.push(server.boxed());
+    }
+    notifier.ready();
 
-        let addr = "0.0.0.0:80".parse().unwrap();
-        let http_server = Server::bind(&addr).serve(util_service);
-        let (http, https) = futures::join!(http_server, tls_server);
-        if let Err(err) = http {
-            error!("Error during http server execution! {}", err);
-        }
-        if let Err(err) = https {
-            error!("Error during https server execution! {}", err);
-        }
-    } else if let Err(err) = tls_server.await {
-        error!("Error during https server execution! {}", err);
+    // Requests in flight may finish within the grace period of the current
+    // config, the servers stop accepting connections right away
+    let expired = async {
+        shutdown.clone().wait().await;
+        notifier.stopping();
+        let timeouts = Timeouts::from_config(state.config().timeouts.as_ref());
+        timeout::run(timeouts.shutdown_grace, futures::future::pending::<()>()).await;
     };
+    tokio::select! {
+        _ = join_all(servers) => info!("Shut down"),
+        _ = expired => warn!("Grace period is over, closing remaining connections"),
+    }
 }
@@ -0,0 +1,398 @@
+use crate::upstream::Upstream;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Upper bounds of the latency histogram buckets in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Arbitrary extension methods would create a time series each
+fn method_label(method: &str) -> &str {
+    match method {
+        "GET" | "HEAD" | "POST" | "PUT" | "DELETE" | "CONNECT" | "OPTIONS" | "TRACE" | "PATCH" => {
+            method
+        }
+        _ => "other",
+    }
+}
+
+fn labels(names: &[&str], values: &[String], extra: Option<(&str, &str)>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if let Some((name, value)) = extra {
+        pairs.push(format!("{}=\"{}\"", name, value));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// Counter with one value per combination of label values
+struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl Counter {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn inc_by(&self, labels: &[&str], value: u64) {
+        let key = labels.iter().map(|label| label.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_insert(0) += value;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        for (values, count) in self.values.lock().unwrap().iter() {
+            let labels = labels(self.labels, values, None);
+            let _ = writeln!(out, "{}{} {}", self.name, labels, count);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    counts: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, Buckets>>,
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn observe(&self, labels: &[&str], duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let key = labels.iter().map(|label| label.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        let buckets = values.entry(key).or_default();
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            buckets.counts[index] += 1;
+        }
+        buckets.count += 1;
+        buckets.sum += secs;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        for (values, buckets) in self.values.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&buckets.counts) {
+                cumulative += count;
+                let bound = bound.to_string();
+                let labels = labels(self.labels, values, Some(("le", &bound)));
+                let _ = writeln!(out, "{}_bucket{} {}", self.name, labels, cumulative);
+            }
+            let labels_inf = labels(self.labels, values, Some(("le", "+Inf")));
+            let _ = writeln!(out, "{}_bucket{} {}", self.name, labels_inf, buckets.count);
+            let labels = labels(self.labels, values, None);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, labels, buckets.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, labels, buckets.count);
+        }
+    }
+}
+
+// Why a request did not match a route
+#[derive(Clone, Copy, Debug)]
+pub enum Unmatched {
+    NotDefined,
+    NotAllowedMethod,
+    Denied,
+    Unauthorized,
+}
+
+impl Unmatched {
+    fn as_str(self) -> &'static str {
+        match self {
+            Unmatched::NotDefined => "not_defined",
+            Unmatched::NotAllowedMethod => "not_allowed_method",
+            Unmatched::Denied => "denied",
+            Unmatched::Unauthorized => "unauthorized",
+        }
+    }
+}
+
+// A finished request as seen by the metrics
+pub struct RequestRecord<'a> {
+    pub route: &'a str,
+    pub method: &'a str,
+    pub status: u16,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub upstream: Option<(String, Duration)>,
+    pub latency: Duration,
+}
+
+// Metrics of all requests since the start, kept across reloads
+pub struct Metrics {
+    requests: Counter,
+    bytes_received: Counter,
+    bytes_sent: Counter,
+    request_duration: Histogram,
+    upstream_latency: Histogram,
+    unmatched: Counter,
+    handshake_failures: AtomicU64,
+    connections: Arc<AtomicI64>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            requests: Counter::new(
+                "heimdall_requests_total",
+                "Requests by route, method and status",
+                &["route", "method", "status"],
+            ),
+            bytes_received: Counter::new(
+                "heimdall_request_bytes_total",
+                "Bytes of request bodies received from clients",
+                &["route"],
+            ),
+            bytes_sent: Counter::new(
+                "heimdall_response_bytes_total",
+                "Bytes of response bodies sent to clients",
+                &["route"],
+            ),
+            request_duration: Histogram::new(
+                "heimdall_request_duration_seconds",
+                "Time until the response was sent to the client",
+                &["route"],
+            ),
+            upstream_latency: Histogram::new(
+                "heimdall_upstream_latency_seconds",
+                "Time until the target sent the response headers",
+                &["route", "upstream"],
+            ),
+            unmatched: Counter::new(
+                "heimdall_unmatched_requests_total",
+                "Requests which did not match a route",
+                &["reason"],
+            ),
+            handshake_failures: AtomicU64::new(0),
+            connections: Arc::new(AtomicI64::new(0)),
+        }
+    }
+}
+
+// Counts a client connection as active until it is dropped
+pub struct ConnectionGuard(Arc<AtomicI64>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn record(&self, request: &RequestRecord) {
+        let status = request.status.to_string();
+        self.requests
+            .inc_by(&[request.route, method_label(request.method), &status], 1);
+        self.bytes_received
+            .inc_by(&[request.route], request.bytes_in);
+        self.bytes_sent.inc_by(&[request.route], request.bytes_out);
+        self.request_duration
+            .observe(&[request.route], request.latency);
+        if let Some((upstream, latency)) = &request.upstream {
+            self.upstream_latency
+                .observe(&[request.route, upstream], *latency);
+        }
+    }
+
+    pub fn unmatched(&self, reason: Unmatched) {
+        self.unmatched.inc_by(&[reason.as_str()], 1);
+    }
+
+    pub fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn open_connection(&self) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.connections.clone())
+    }
+
+    // Prometheus text exposition format, the pool counters are taken from
+    // the upstreams of the active router
+    pub fn render(&self, upstreams: &[Arc<Upstream>]) -> String {
+        let mut out = String::new();
+        self.requests.render(&mut out);
+        self.bytes_received.render(&mut out);
+        self.bytes_sent.render(&mut out);
+        self.request_duration.render(&mut out);
+        self.upstream_latency.render(&mut out);
+        self.unmatched.render(&mut out);
+        header(
+            &mut out,
+            "heimdall_tls_handshake_failures_total",
+            "Failed or timed out TLS handshakes",
+            "counter",
+        );
+        let _ = writeln!(
+            out,
+            "heimdall_tls_handshake_failures_total {}",
+            self.handshake_failures.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "heimdall_active_connections",
+            "Open client connections",
+            "gauge",
+        );
+        let _ = writeln!(
+            out,
+            "heimdall_active_connections {}",
+            self.connections.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "heimdall_upstream_connections_total",
+            "Connections established to the target",
+            "counter",
+        );
+        for upstream in upstreams {
+            let labels = labels(&["upstream"], &[upstream.addr().to_string()], None);
+            let _ = writeln!(
+                out,
+                "heimdall_upstream_connections_total{} {}",
+                labels,
+                upstream.stats().connections()
+            );
+        }
+        header(
+            &mut out,
+            "heimdall_upstream_reused_requests_total",
+            "Requests sent over an already established connection",
+            "counter",
+        );
+        for upstream in upstreams {
+            let labels = labels(&["upstream"], &[upstream.addr().to_string()], None);
+            let _ = writeln!(
+                out,
+                "heimdall_upstream_reused_requests_total{} {}",
+                labels,
+                upstream.stats().reused()
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metrics, RequestRecord, Unmatched};
+    use crate::config::PoolDefinition;
+    use crate::upstream::Upstream;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        let record = RequestRecord {
+            route: "a.test/",
+            method: "GET",
+            status: 200,
+            bytes_in: 10,
+            bytes_out: 100,
+            upstream: Some(("127.0.0.1:8000".to_owned(), Duration::from_millis(20))),
+            latency: Duration::from_millis(30),
+        };
+        metrics.record(&record);
+        metrics.record(&record);
+        for method in &["FOO", "BAR"] {
+            metrics.record(&RequestRecord {
+                method,
+                upstream: record.upstream.clone(),
+                ..record
+            });
+        }
+        metrics.unmatched(Unmatched::NotDefined);
+        metrics.handshake_failed();
+        let connection = metrics.open_connection();
+
+        let upstream = Upstream::new(
+            "127.0.0.1:8000".parse().unwrap(),
+            &PoolDefinition::default(),
+            None,
+            None,
+        );
+
+        let text = metrics.render(&[Arc::new(upstream)]);
+        let has = |line: &str| text.lines().any(|l| l == line);
+        assert!(has(
+            "heimdall_requests_total{route=\"a.test/\",method=\"GET\",status=\"200\"} 2"
+        ));
+        assert!(has(
+            "heimdall_requests_total{route=\"a.test/\",method=\"other\",status=\"200\"} 2"
+        ));
+        assert!(has("heimdall_response_bytes_total{route=\"a.test/\"} 400"));
+        assert!(has(
+            "heimdall_upstream_latency_seconds_bucket{route=\"a.test/\",upstream=\"127.0.0.1:8000\",le=\"0.01\"} 0"
+        ));
+        assert!(has(
+            "heimdall_upstream_latency_seconds_bucket{route=\"a.test/\",upstream=\"127.0.0.1:8000\",le=\"0.025\"} 4"
+        ));
+        assert!(has(
+            "heimdall_request_duration_seconds_count{route=\"a.test/\"} 4"
+        ));
+        assert!(has(
+            "heimdall_unmatched_requests_total{reason=\"not_defined\"} 1"
+        ));
+        assert!(has("heimdall_tls_handshake_failures_total 1"));
+        assert!(has("heimdall_active_connections 1"));
+        assert!(has(
+            "heimdall_upstream_connections_total{upstream=\"127.0.0.1:8000\"} 0"
+        ));
+        assert!(has(
+            "heimdall_upstream_reused_requests_total{upstream=\"127.0.0.1:8000\"} 0"
+        ));
+        drop(connection);
+        assert!(metrics
+            .render(&[])
+            .contains("heimdall_active_connections 0"));
+    }
+}
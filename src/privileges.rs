@@ -0,0 +1,206 @@
+#![allow(non_local_definitions)]
+
+use crate::config::Config;
+use failure::Fail;
+use log::{info, warn};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+// Size of the buffer for the strings of passwd and group entries
+const ENTRY_BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Fail)]
+pub enum PrivilegeError {
+    #[fail(display = "Unknown user '{}'", name)]
+    UnknownUser { name: String },
+    #[fail(display = "Unknown group '{}'", name)]
+    UnknownGroup { name: String },
+    #[fail(display = "Could not {}: {}", action, err)]
+    Os { action: String, err: io::Error },
+    #[fail(display = "Root privileges could be regained")]
+    Regainable,
+    #[fail(display = "'{}' cannot be used with 'chroot'", feature)]
+    Chroot { feature: String },
+}
+
+fn os_error(action: String) -> PrivilegeError {
+    PrivilegeError::Os {
+        action,
+        err: io::Error::last_os_error(),
+    }
+}
+
+fn c_string(value: &str) -> Result<CString, PrivilegeError> {
+    CString::new(value).map_err(|_| PrivilegeError::Os {
+        action: format!("use '{}'", value.replace('\0', "\\0")),
+        err: io::Error::from(io::ErrorKind::InvalidInput),
+    })
+}
+
+// Uid and primary gid of the user
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t), PrivilegeError> {
+    let c_name = c_string(name)?;
+    let mut entry = MaybeUninit::<libc::passwd>::uninit();
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let code = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            entry.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if code != 0 {
+        return Err(PrivilegeError::Os {
+            action: format!("look up user '{}'", name),
+            err: io::Error::from_raw_os_error(code),
+        });
+    }
+    if result.is_null() {
+        return Err(PrivilegeError::UnknownUser {
+            name: name.to_owned(),
+        });
+    }
+    let entry = unsafe { entry.assume_init() };
+    Ok((entry.pw_uid, entry.pw_gid))
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t, PrivilegeError> {
+    let c_name = c_string(name)?;
+    let mut entry = MaybeUninit::<libc::group>::uninit();
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let code = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            entry.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if code != 0 {
+        return Err(PrivilegeError::Os {
+            action: format!("look up group '{}'", name),
+            err: io::Error::from_raw_os_error(code),
+        });
+    }
+    if result.is_null() {
+        return Err(PrivilegeError::UnknownGroup {
+            name: name.to_owned(),
+        });
+    }
+    Ok(unsafe { entry.assume_init() }.gr_gid)
+}
+
+// Switches to the configured user and group after the sockets are bound
+// and the certificates are loaded, optionally inside a chroot. The ids are
+// looked up before the chroot as it usually has no user database.
+// The process ids apply to all threads, including those of the runtime.
+pub fn drop_privileges(config: &Config) -> Result<(), PrivilegeError> {
+    // Paths are not rewritten, features which access files while running
+    // would use the ones outside of the chroot
+    if config.chroot.is_some() {
+        let features = [
+            ("acme", config.acme.is_some()),
+            ("acme_web_root", config.acme_web_root.is_some()),
+        ];
+        if let Some((feature, _)) = features.iter().find(|(_, used)| *used) {
+            return Err(PrivilegeError::Chroot {
+                feature: (*feature).to_owned(),
+            });
+        }
+    }
+    let user = match &config.user {
+        Some(name) => Some((name, lookup_user(name)?)),
+        None => None,
+    };
+    let gid = match (&config.group, &user) {
+        (Some(name), _) => Some(lookup_group(name)?),
+        (None, Some((_, (_, gid)))) => Some(*gid),
+        (None, None) => None,
+    };
+
+    if let Some(path) = &config.chroot {
+        let c_path = c_string(path)?;
+        if unsafe { libc::chroot(c_path.as_ptr()) } != 0 {
+            return Err(os_error(format!("chroot to '{}'", path)));
+        }
+        let root = CStr::from_bytes_with_nul(b"/\0").unwrap();
+        if unsafe { libc::chdir(root.as_ptr()) } != 0 {
+            return Err(os_error("change to the new root".to_owned()));
+        }
+        info!("Changed root to '{}'", path);
+    }
+
+    if let Some(gid) = gid {
+        // Supplementary groups of root are dropped as well
+        if unsafe { libc::geteuid() } == 0 && unsafe { libc::setgroups(1, &gid) } != 0 {
+            return Err(os_error("drop the supplementary groups".to_owned()));
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(os_error(format!("switch to group {}", gid)));
+        }
+    }
+    if let Some((name, (uid, _))) = user {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(os_error(format!("switch to user '{}'", name)));
+        }
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(PrivilegeError::Regainable);
+        }
+        info!("Running as user '{}' ({}), group {}", name, uid, unsafe {
+            libc::getgid()
+        });
+    } else if unsafe { libc::geteuid() } == 0 {
+        warn!("Running as root, set 'user' to drop the privileges after startup!");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{drop_privileges, lookup_group, lookup_user, PrivilegeError};
+    use crate::config::Config;
+
+    #[test]
+    fn lookup() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        match lookup_user("heimdall-unknown-user") {
+            Err(PrivilegeError::UnknownUser { name }) => assert_eq!(name, "heimdall-unknown-user"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match lookup_group("heimdall-unknown-group") {
+            Err(PrivilegeError::UnknownGroup { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(lookup_user("invalid\0name").is_err());
+    }
+
+    #[test]
+    fn unknown_user() {
+        // Lookups fail before anything is changed
+        let config = Config {
+            user: Some("heimdall-unknown-user".to_owned()),
+            chroot: Some("/nonexistent".to_owned()),
+            ..Default::default()
+        };
+        assert!(drop_privileges(&config).is_err());
+        assert!(drop_privileges(&Config::default()).is_ok());
+
+        let config = Config {
+            chroot: Some("/nonexistent".to_owned()),
+            acme_web_root: Some("/var/www".to_owned()),
+            ..Default::default()
+        };
+        match drop_privileges(&config) {
+            Err(PrivilegeError::Chroot { feature }) => assert_eq!(feature, "acme_web_root"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
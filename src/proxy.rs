@@ -1,31 +1,253 @@
-use hyper::header::{HeaderMap, HeaderValue};
-use hyper::Client;
-use hyper::{Request, Uri};
+#![allow(non_local_definitions)]
+use crate::access_log::UpstreamInfo;
+use crate::balancer::Balancer;
+use crate::breaker::{Outcome, Ticket};
+use crate::forwarded::{self, Peer};
+use crate::headers::{HeaderRules, Variables};
+use crate::retry::RequestBody;
+use crate::router::Forward;
+use crate::timeout;
+use crate::upstream::Upstream;
+use failure::Fail;
+use hyper::header::{HeaderMap, HeaderValue, CONNECTION, CONTENT_TYPE, HOST, RETRY_AFTER};
+use hyper::{Body, Request, Response, StatusCode, Uri};
 use lazy_static::lazy_static;
-use std::net::IpAddr;
+use log::{error, warn};
+use std::error::Error;
+use std::io;
+use std::time::{Duration, Instant};
 use unicase::Ascii;
 
-pub fn call(request: Request<hyper::Body>) -> hyper::client::ResponseFuture {
-    Client::new().request(request)
+#[derive(Debug, Fail)]
+pub enum ProxyError {
+    #[fail(display = "Upstream error: {}", err)]
+    Upstream { err: hyper::Error },
+    #[fail(display = "No response within {:?}", timeout)]
+    Timeout { timeout: Duration },
+}
+
+impl From<hyper::Error> for ProxyError {
+    fn from(err: hyper::Error) -> ProxyError {
+        ProxyError::Upstream { err }
+    }
+}
+
+impl ProxyError {
+    // Status of the response sent to the client
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::Upstream { err } if is_timeout(err) => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::Upstream { .. } => StatusCode::BAD_GATEWAY,
+            ProxyError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
+
+// Whether the error was caused by a timed out connection attempt
+fn is_timeout(err: &hyper::Error) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            if err.kind() == io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+pub async fn call(
+    upstream: &Upstream,
+    ticket: Ticket,
+    request: Request<hyper::Body>,
+    response_timeout: Option<Duration>,
+) -> Result<Response<hyper::Body>, ProxyError> {
+    let result = match timeout::run(response_timeout, upstream.request(request)).await {
+        Some(result) => result.map_err(ProxyError::from),
+        None => Err(ProxyError::Timeout {
+            timeout: response_timeout.unwrap(),
+        }),
+    };
+    record(upstream, ticket, result.as_ref().map(Response::status));
+    result
+}
+
+// Reports the result of a request to the circuit breaker of the upstream
+pub fn record<E>(upstream: &Upstream, ticket: Ticket, result: Result<StatusCode, E>) {
+    if let Some(breaker) = upstream.breaker() {
+        breaker.record(
+            ticket,
+            match result {
+                Ok(status) if status.is_server_error() => Outcome::ServerError,
+                Ok(_) => Outcome::Success,
+                Err(_) => Outcome::Failure,
+            },
+        );
+    }
+}
+
+// Sends the request to a backend of the matched route, failed attempts are
+// sent again if the retry policy of the route allows it
+pub async fn forward(request: Request<Body>, peer: &Peer, forward: &Forward) -> Response<Body> {
+    let route = &forward.route;
+    let variables = Variables::new(&request, peer, route.balancer.route());
+    let (parts, body) = request.into_parts();
+    let mut body = match &route.retry {
+        Some(retry) if retry.allows(&parts.method) => {
+            let buffer = RequestBody::buffer(body, retry.max_body_bytes());
+            match timeout::run(route.body_timeout, buffer).await {
+                Some(body) => body,
+                None => {
+                    warn!(
+                        "Request body for route {} was not received in time!",
+                        route.balancer.route()
+                    );
+                    return request_timeout();
+                }
+            }
+        }
+        _ => RequestBody::Streaming(Some(body)),
+    };
+    let retry = route.retry.as_ref().filter(|_| body.is_buffered());
+    let mut attempt = 1;
+    let mut tried = vec![];
+    loop {
+        let mut request = Request::new(body.take());
+        *request.method_mut() = parts.method.clone();
+        *request.uri_mut() = parts.uri.clone();
+        *request.version_mut() = parts.version;
+        *request.headers_mut() = parts.headers.clone();
+        let upstream = match route.balancer.select(&request, peer.client_ip, &tried) {
+            Some(upstream) => upstream,
+            None => return unavailable(&route.balancer),
+        };
+        let request = prepare(
+            request,
+            peer,
+            forward.uri(&upstream),
+            route.request_headers.as_ref(),
+            &variables,
+        )
+        .await;
+        let started = Instant::now();
+        let result = call(&upstream, upstream.ticket, request, route.response_timeout).await;
+        if let Some(retry) = retry {
+            if attempt < retry.attempts() && retry.should_retry(&result) {
+                let delay = retry.backoff(attempt);
+                let reason = match &result {
+                    Ok(response) => format!("Status {}", response.status()),
+                    Err(err) => format!("{}", err),
+                };
+                warn!(
+                    "Attempt {} for route {} to {} failed, retrying in {:?}! {}",
+                    attempt,
+                    route.balancer.route(),
+                    upstream.addr(),
+                    delay,
+                    reason
+                );
+                tokio::time::delay_for(delay).await;
+                tried.push(upstream.addr());
+                attempt += 1;
+                continue;
+            }
+        }
+        let mut response = match result {
+            Ok(mut response) => {
+                if let Some(rules) = &route.response_headers {
+                    rules.apply(response.headers_mut(), &variables);
+                }
+                response
+            }
+            Err(err) => {
+                error!(
+                    "Request for route {} to {} failed! {}",
+                    route.balancer.route(),
+                    upstream.addr(),
+                    err
+                );
+                error_response(&err)
+            }
+        };
+        response.extensions_mut().insert(UpstreamInfo {
+            addr: upstream.addr(),
+            latency: started.elapsed(),
+        });
+        return response;
+    }
+}
+
+// Response if no backend of a route is available
+pub fn unavailable(balancer: &Balancer) -> Response<Body> {
+    let mut response = Response::builder().status(StatusCode::SERVICE_UNAVAILABLE);
+    if let Some(retry_after) = balancer.retry_after() {
+        // Round up so clients do not retry before the breaker allows it
+        let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        response = response.header(RETRY_AFTER, secs.max(1));
+    }
+    response.body(Body::from("No healthy backend!")).unwrap()
+}
+
+fn request_timeout() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::REQUEST_TIMEOUT)
+        .header(CONNECTION, "close")
+        .body(Body::from("Request body not received in time!"))
+        .unwrap()
+}
+
+// Response for a failed upstream request, the cause is only logged
+pub fn error_response(err: &ProxyError) -> Response<Body> {
+    let status = err.status();
+    let message = match status {
+        StatusCode::GATEWAY_TIMEOUT => "The upstream server did not respond in time",
+        _ => "The upstream server could not be reached or sent an invalid response",
+    };
+    let body = serde_json::json!({
+        "status": status.as_u16(),
+        "error": status.canonical_reason().unwrap_or_default(),
+        "message": message,
+    });
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
 }
 
 pub async fn prepare(
     mut request: Request<hyper::Body>,
-    source: IpAddr,
+    peer: &Peer,
     target: Uri,
+    rules: Option<&HeaderRules>,
+    variables: &Variables,
 ) -> Request<hyper::Body> {
     // Strip Hop-by-Hop headers
     *request.headers_mut() = strip_hbh(request.headers());
 
+    // The authority of HTTP/2 requests takes precedence over the host header
+    let host = match request.uri().authority() {
+        Some(authority) => HeaderValue::from_str(authority.as_str()).ok(),
+        None => request.headers().get(HOST).cloned(),
+    };
+
     // Redirect to forward uri
     *request.uri_mut() = target;
 
     // Add forwarding information
-    let fwd_header = "x-forwarded-for";
-    request
-        .headers_mut()
-        .entry(fwd_header)
-        .or_insert_with(|| format!("{}", source).parse().unwrap());
+    forwarded::apply(request.headers_mut(), peer, host);
+
+    // The target sees the same request id as the access log, ids sent by
+    // untrusted clients are replaced
+    if let Ok(id) = HeaderValue::from_str(&variables.request_id) {
+        request.headers_mut().insert("x-request-id", id);
+    }
+
+    // Route specific changes come last so they can override anything
+    if let Some(rules) = rules {
+        rules.apply(request.headers_mut(), variables);
+    }
     request
 }
 
@@ -33,7 +255,7 @@ fn strip_hbh(headers: &HeaderMap<HeaderValue>) -> HeaderMap<HeaderValue> {
     let mut result = HeaderMap::new();
     for (k, v) in headers.iter() {
         if !is_hbh_header(k.as_str()) {
-            result.insert(k.clone(), v.clone());
+            result.append(k.clone(), v.clone());
         }
     }
     result
@@ -58,8 +280,17 @@ fn is_hbh_header(name: &str) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use super::{strip_hbh, HBH_HEADERS};
+    use super::{call, error_response, forward, prepare, strip_hbh, HBH_HEADERS};
+    use crate::breaker::Ticket;
+    use crate::config::Config;
+    use crate::forwarded::Peer;
+    use crate::headers::Variables;
+    use crate::router::{Router, RouterResult};
+    use crate::upstream::Upstream;
     use hyper::header::{HeaderMap, HeaderValue};
+    use hyper::{Body, Request, StatusCode};
+    use std::net::TcpListener;
+    use std::time::Duration;
 
     fn headers_map() -> HeaderMap<HeaderValue> {
         let mut headers = HeaderMap::new();
@@ -89,4 +320,142 @@ mod tests {
         assert_eq!(headers.len(), 1);
         assert_eq!(headers[HEADER], HEADER);
     }
+
+    #[tokio::test]
+    async fn repeated_headers() {
+        // Proxies in front may send their forwarding headers as separate lines
+        let request = Request::get("/")
+            .header("x-forwarded-for", "198.51.100.1")
+            .header("x-forwarded-for", "10.0.0.2")
+            .header("forwarded", "for=198.51.100.1")
+            .header("forwarded", "for=10.0.0.2")
+            .header("connection", "close")
+            .header("accept", "text/html")
+            .header("accept", "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let peer = Peer {
+            ip: "10.0.0.3".parse().unwrap(),
+            client_ip: "198.51.100.1".parse().unwrap(),
+            local_port: 443,
+            trusted: true,
+        };
+        let variables = Variables::new(&request, &peer, "a.test/");
+        let target = "http://127.0.0.1:8000/".parse().unwrap();
+        let request = prepare(request, &peer, target, None, &variables).await;
+        let headers = request.headers();
+        assert_eq!(
+            headers["x-forwarded-for"],
+            "198.51.100.1, 10.0.0.2, 10.0.0.3"
+        );
+        assert_eq!(
+            headers["forwarded"],
+            "for=198.51.100.1, for=10.0.0.2, for=10.0.0.3;proto=https"
+        );
+        assert_eq!(headers.get_all("accept").iter().count(), 2);
+        assert!(headers.get("connection").is_none());
+        assert_eq!(headers["x-request-id"], variables.request_id.as_str());
+    }
+
+    #[tokio::test]
+    async fn untrusted_request_id() {
+        let request = Request::get("/")
+            .header("x-request-id", "chosen-by-client")
+            .body(Body::empty())
+            .unwrap();
+        let peer = Peer {
+            ip: "198.51.100.1".parse().unwrap(),
+            client_ip: "198.51.100.1".parse().unwrap(),
+            local_port: 443,
+            trusted: false,
+        };
+        let variables = Variables::new(&request, &peer, "a.test/");
+        let target = "http://127.0.0.1:8000/".parse().unwrap();
+        let request = prepare(request, &peer, target, None, &variables).await;
+        let ids: Vec<_> = request.headers().get_all("x-request-id").iter().collect();
+        assert_eq!(ids.len(), 1);
+        assert_ne!(ids[0], "chosen-by-client");
+        assert_eq!(ids[0], variables.request_id.as_str());
+    }
+
+    #[tokio::test]
+    async fn slow_request_body() {
+        let config: Config = toml::from_str(
+            r#"
+            redirect_to_https = false
+
+            [[routes]]
+            source = '/'
+            target = '127.0.0.1:8000'
+            allowed_methods = []
+            retry = {}
+            timeouts = { upstream_response_secs = 1 }
+            "#,
+        )
+        .unwrap();
+        let router = Router::from_config(config).unwrap();
+        // The client never finishes the body
+        let (_sender, body) = Body::channel();
+        let request = Request::put("/").body(body).unwrap();
+        let forward_to = match router.eval(&request, "127.0.0.1".parse().unwrap()) {
+            RouterResult::Success(forward_to) => forward_to,
+            _ => panic!("Route did not match"),
+        };
+        let peer = Peer {
+            ip: "127.0.0.1".parse().unwrap(),
+            client_ip: "127.0.0.1".parse().unwrap(),
+            local_port: 443,
+            trusted: false,
+        };
+        let response = forward(request, &peer, &forward_to).await;
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn bad_gateway() {
+        // Bind and drop a listener to get a port nobody listens on
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let upstream = Upstream::new(addr, &Default::default(), None, None);
+        let request = Request::get(format!("http://{}/", addr))
+            .body(Body::empty())
+            .unwrap();
+        let err = call(&upstream, Ticket::default(), request, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_GATEWAY);
+
+        let response = error_response(&err);
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], 502);
+        assert_eq!(body["error"], "Bad Gateway");
+    }
+
+    #[tokio::test]
+    async fn gateway_timeout() {
+        // Accepts connections but never answers
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        let upstream = Upstream::new(addr, &Default::default(), None, None);
+        let request = Request::get(format!("http://{}/", addr))
+            .body(Body::empty())
+            .unwrap();
+        let timeout = Some(Duration::from_millis(50));
+        let err = call(&upstream, Ticket::default(), request, timeout)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(error_response(&err).status(), StatusCode::GATEWAY_TIMEOUT);
+    }
 }
@@ -0,0 +1,208 @@
+use crate::acl::{idempotent_methods, parse_allowed_methods, AllowedMethods};
+use crate::config::{ConfigError, RetryDefinition};
+use crate::proxy::ProxyError;
+use futures_util::stream::{self, StreamExt};
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Method, Response, StatusCode};
+use rand::Rng;
+use std::time::Duration;
+
+const DEFAULT_ATTEMPTS: u32 = 3;
+const DEFAULT_BACKOFF_MS: u64 = 50;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 1000;
+const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+
+pub struct RetryPolicy {
+    // Attempts including the first one
+    attempts: u32,
+    on_connect_error: bool,
+    on_status: Vec<StatusCode>,
+    methods: AllowedMethods,
+    backoff: Duration,
+    max_backoff: Duration,
+    // Larger request bodies are streamed and not retried
+    max_body_bytes: usize,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &RetryDefinition) -> Result<Self, ConfigError> {
+        let methods = if config.methods.is_empty() {
+            idempotent_methods()
+        } else {
+            parse_allowed_methods(config.methods.clone())?
+        };
+        Ok(Self {
+            attempts: config.attempts.unwrap_or(DEFAULT_ATTEMPTS).max(1),
+            on_connect_error: config.on_connect_error.unwrap_or(true),
+            on_status: config
+                .on_status
+                .iter()
+                .filter_map(|status| StatusCode::from_u16(*status).ok())
+                .collect(),
+            methods,
+            backoff: Duration::from_millis(config.backoff_ms.unwrap_or(DEFAULT_BACKOFF_MS)),
+            max_backoff: Duration::from_millis(
+                config.max_backoff_ms.unwrap_or(DEFAULT_MAX_BACKOFF_MS),
+            ),
+            max_body_bytes: config.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES),
+        })
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+
+    pub fn allows(&self, method: &Method) -> bool {
+        self.attempts > 1 && self.methods.contains(method)
+    }
+
+    // Whether the result of an attempt should be retried
+    pub fn should_retry(&self, result: &Result<Response<Body>, ProxyError>) -> bool {
+        match result {
+            Ok(response) => self.on_status.contains(&response.status()),
+            Err(ProxyError::Upstream { err }) => self.on_connect_error && err.is_connect(),
+            Err(ProxyError::Timeout { .. }) => false,
+        }
+    }
+
+    // Exponential backoff before the given retry, randomized between half
+    // and the full delay so clients do not retry in lockstep
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(retry.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        let max = self.backoff.saturating_mul(factor).min(self.max_backoff);
+        let millis = max.as_millis() as u64;
+        if millis == 0 {
+            return max;
+        }
+        Duration::from_millis(rand::thread_rng().gen_range(millis / 2, millis + 1))
+    }
+}
+
+// Request body which can be sent again if it was buffered
+pub enum RequestBody {
+    Buffered(Bytes),
+    Streaming(Option<Body>),
+}
+
+impl RequestBody {
+    // Buffers the body if it is not larger than the limit, otherwise the
+    // already read part is sent followed by the rest of the stream
+    pub async fn buffer(mut body: Body, limit: usize) -> Self {
+        let mut chunks: Vec<Bytes> = vec![];
+        let mut size = 0;
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => {
+                    size += chunk.len();
+                    chunks.push(chunk);
+                    if size > limit {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    // Let the upstream request fail with the same error
+                    let rest = stream::iter(chunks.into_iter().map(Ok))
+                        .chain(stream::once(async { Err(err) }));
+                    return RequestBody::Streaming(Some(Body::wrap_stream(rest)));
+                }
+            }
+        }
+        if size > limit {
+            let rest = stream::iter(chunks.into_iter().map(Ok)).chain(body);
+            return RequestBody::Streaming(Some(Body::wrap_stream(rest)));
+        }
+        RequestBody::Buffered(chunks.concat().into())
+    }
+
+    pub fn is_buffered(&self) -> bool {
+        matches!(self, RequestBody::Buffered(_))
+    }
+
+    // Body for the next attempt, a streamed body can only be sent once
+    pub fn take(&mut self) -> Body {
+        match self {
+            RequestBody::Buffered(bytes) => Body::from(bytes.clone()),
+            RequestBody::Streaming(body) => body.take().unwrap_or_else(Body::empty),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RequestBody, RetryPolicy};
+    use crate::config::RetryDefinition;
+    use crate::proxy::ProxyError;
+    use hyper::{Body, Method, Response};
+    use std::time::Duration;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::from_config(&RetryDefinition {
+            attempts: Some(3),
+            on_connect_error: None,
+            on_status: vec![503],
+            methods: vec![],
+            backoff_ms: Some(100),
+            max_backoff_ms: Some(300),
+            max_body_bytes: Some(8),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn retry_conditions() {
+        let policy = policy();
+        assert!(policy.allows(&Method::GET));
+        assert!(policy.allows(&Method::PUT));
+        assert!(!policy.allows(&Method::POST));
+        assert!(!policy.allows(&Method::PATCH));
+
+        let status = |status: u16| -> Result<Response<Body>, ProxyError> {
+            Ok(Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .unwrap())
+        };
+        assert!(policy.should_retry(&status(503)));
+        assert!(!policy.should_retry(&status(500)));
+        assert!(!policy.should_retry(&status(200)));
+        let timeout = Err(ProxyError::Timeout {
+            timeout: Duration::from_secs(1),
+        });
+        assert!(!policy.should_retry(&timeout));
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = policy();
+        for _ in 0..10 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let capped = policy.backoff(10);
+            assert!(capped >= Duration::from_millis(150) && capped <= Duration::from_millis(300));
+        }
+    }
+
+    #[tokio::test]
+    async fn buffering() {
+        let mut body = RequestBody::buffer(Body::from("short"), 8).await;
+        assert!(body.is_buffered());
+        for _ in 0..2 {
+            let bytes = hyper::body::to_bytes(body.take()).await.unwrap();
+            assert_eq!(&bytes[..], b"short");
+        }
+
+        let chunks: Vec<Result<_, std::io::Error>> = vec![Ok("0123"), Ok("4567"), Ok("89")];
+        let stream = Body::wrap_stream(futures_util::stream::iter(chunks));
+        let mut body = RequestBody::buffer(stream, 6).await;
+        assert!(!body.is_buffered());
+        let bytes = hyper::body::to_bytes(body.take()).await.unwrap();
+        assert_eq!(&bytes[..], b"0123456789");
+        assert!(hyper::body::to_bytes(body.take()).await.unwrap().is_empty());
+    }
+}
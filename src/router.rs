@@ -1,10 +1,24 @@
-use crate::acl::{parse_allowed_methods, AllowedMethods};
-use crate::config::Config;
+use crate::acl::{parse_allowed_methods, AllowedMethods, IpAcl};
+use crate::auth::{BasicAuth, Check};
+use crate::balancer::{Backend, Balancer};
+use crate::config::{CircuitBreakerDefinition, Config, ConfigError, PoolDefinition};
+use crate::forwarded::TrustedProxies;
+use crate::headers::HeaderRules;
+use crate::health::HealthCheck;
+use crate::retry::RetryPolicy;
+use crate::security::SecurityHeaders;
+use crate::timeout::Timeouts;
+use crate::upstream::Upstream;
+use crate::util::{host_matches_wildcard, request_host};
+use hyper::header::HeaderValue;
 use hyper::http::uri::{Authority, Scheme};
 use hyper::{Body, Request, Uri};
 use path_tree::PathTree;
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 fn make_path(path: String) -> String {
     let path = path.replace("//", "/");
@@ -19,52 +33,273 @@ fn make_path(path: String) -> String {
     path
 }
 
+// Settings of a route which apply once it matched
+pub struct Route {
+    pub balancer: Arc<Balancer>,
+    pub response_timeout: Option<Duration>,
+    // Reading a request body which is buffered for retries
+    pub body_timeout: Option<Duration>,
+    pub retry: Option<RetryPolicy>,
+    pub upgrade: bool,
+    pub upgrade_idle: Option<Duration>,
+    pub request_headers: Option<HeaderRules>,
+    pub response_headers: Option<HeaderRules>,
+    pub security_headers: Option<SecurityHeaders>,
+    // Removes the credentials of basic auth before forwarding
+    pub strip_authorization: bool,
+}
+
 #[derive(Clone)]
 pub struct Target {
-    addr: SocketAddr,
+    route: Arc<Route>,
     path: Option<String>,
     allowed_methods: AllowedMethods,
+    ip_acl: Option<IpAcl>,
+    basic_auth: Option<Arc<BasicAuth>>,
 }
 
 #[derive(Clone)]
 pub struct Router {
-    routes: PathTree<Target>,
+    // Routes for exact host names
+    hosts: HashMap<String, PathTree<Target>>,
+    // Routes for wildcard host names, stored by suffix ('.example.com')
+    // and sorted from the most to the least specific
+    wildcards: Vec<(String, PathTree<Target>)>,
+    // Routes without a host, used if no host specific routes match
+    default: PathTree<Target>,
+    upstreams: Vec<Arc<Upstream>>,
+    balancers: Vec<Arc<Balancer>>,
+    trusted_proxies: TrustedProxies,
+    // Applies to all requests, before the one of the route
+    ip_acl: Option<IpAcl>,
+    // Security headers for responses which did not match a route
+    security_headers: Option<SecurityHeaders>,
+}
+
+// Destination of a request which matched a route, the backend is chosen
+// by the balancer of the route
+pub struct Forward {
+    pub route: Arc<Route>,
+    path_and_query: String,
+}
+
+impl Forward {
+    // Uri of the request on the given backend
+    pub fn uri(&self, upstream: &Upstream) -> Uri {
+        let addr = upstream.addr();
+        Uri::builder()
+            .scheme(Scheme::HTTP)
+            .authority(Authority::from_str(&format!("{}:{}", addr.ip(), addr.port())).unwrap())
+            .path_and_query(self.path_and_query.as_str())
+            .build()
+            .unwrap()
+    }
 }
 
-#[derive(Debug, PartialEq)]
 pub enum RouterResult {
-    Success(Uri),
+    Success(Forward),
     NotDefined,
     NotAllowedMethod,
+    // The client address is not allowed by the global or route ACL
+    Denied,
+    // Basic auth credentials are missing or wrong, holds the challenge
+    Unauthorized(HeaderValue),
+    // The credentials have to be verified before the request is evaluated
+    // again, hashing is too slow for the runtime threads
+    Unverified(Arc<BasicAuth>),
 }
 
 impl Router {
-    pub fn from_config(config: Config) -> Self {
-        let mut routes = PathTree::new();
+    // Fails on invalid methods and routes without backends
+    pub fn from_config(config: Config) -> Result<Self, ConfigError> {
+        let mut router = Self::empty();
+        router.trusted_proxies = TrustedProxies::from_config(&config.trusted_proxies);
+        router.ip_acl = config.ip_acl.as_ref().map(IpAcl::from_config);
+        router.security_headers =
+            SecurityHeaders::from_config(config.security_headers.as_ref(), None);
+        let pool = config.pool.unwrap_or_default();
+        let breaker = config.circuit_breaker;
+        let timeouts = Timeouts::from_config(config.timeouts.as_ref());
         for route in config.routes {
-            routes.insert(
-                &make_path(route.source),
+            let timeouts = timeouts.for_route(route.timeouts.as_ref());
+            let basic_auth = route
+                .basic_auth
+                .as_ref()
+                .map(|auth| Arc::new(BasicAuth::from_config(auth)));
+            let backends = route
+                .target
+                .backends()
+                .into_iter()
+                .map(|(addr, weight)| {
+                    let upstream =
+                        router.upstream(addr, &pool, breaker.as_ref(), timeouts.upstream_connect);
+                    Backend::new(upstream, weight)
+                })
+                .collect();
+            let balancer = Balancer::new(
+                format!("{}{}", route.host.as_deref().unwrap_or(""), route.source),
+                backends,
+                route.balance.unwrap_or_default(),
+                route.hash_header,
+                route.health_check.as_ref().map(HealthCheck::from_config),
+            )?;
+            let retry = match &route.retry {
+                Some(retry) => Some(RetryPolicy::from_config(retry)?),
+                None => None,
+            };
+            router.insert(
+                route.host.as_deref(),
+                &route.source,
                 Target {
-                    addr: route.target,
+                    route: Arc::new(Route {
+                        balancer: Arc::new(balancer),
+                        response_timeout: timeouts.upstream_response,
+                        body_timeout: timeouts.upstream_response.or(timeouts.header_read),
+                        retry,
+                        upgrade: route.upgrade.unwrap_or(false),
+                        upgrade_idle: timeouts.upgrade_idle,
+                        request_headers: route
+                            .request_headers
+                            .as_ref()
+                            .map(HeaderRules::from_config),
+                        response_headers: route
+                            .response_headers
+                            .as_ref()
+                            .map(HeaderRules::from_config),
+                        security_headers: SecurityHeaders::from_config(
+                            config.security_headers.as_ref(),
+                            route.security_headers.as_ref(),
+                        ),
+                        strip_authorization: basic_auth
+                            .as_ref()
+                            .is_some_and(|auth| !auth.forward_authorization()),
+                    }),
                     path: route.target_path,
-                    allowed_methods: parse_allowed_methods(route.allowed_methods),
+                    allowed_methods: parse_allowed_methods(route.allowed_methods)?,
+                    ip_acl: route.ip_acl.as_ref().map(IpAcl::from_config),
+                    basic_auth,
                 },
             );
         }
-        Self { routes }
+        Ok(router)
+    }
+
+    // Routes to the same address share the upstream and its pool unless
+    // they use different connect timeouts
+    fn upstream(
+        &mut self,
+        addr: SocketAddr,
+        pool: &PoolDefinition,
+        breaker: Option<&CircuitBreakerDefinition>,
+        connect_timeout: Option<Duration>,
+    ) -> Arc<Upstream> {
+        if let Some(upstream) = self
+            .upstreams
+            .iter()
+            .find(|u| u.addr() == addr && u.connect_timeout() == connect_timeout)
+        {
+            return upstream.clone();
+        }
+        let upstream = Arc::new(Upstream::new(addr, pool, breaker, connect_timeout));
+        self.upstreams.push(upstream.clone());
+        upstream
+    }
+
+    fn empty() -> Self {
+        Self {
+            hosts: HashMap::new(),
+            wildcards: Vec::new(),
+            default: PathTree::new(),
+            upstreams: Vec::new(),
+            balancers: Vec::new(),
+            trusted_proxies: TrustedProxies::default(),
+            ip_acl: None,
+            security_headers: None,
+        }
+    }
+
+    fn insert(&mut self, host: Option<&str>, source: &str, target: Target) {
+        let path = make_path(source.to_owned());
+        let routes = match host.map(|host| host.trim_end_matches('.').to_lowercase()) {
+            None => &mut self.default,
+            Some(host) if host.starts_with("*.") => {
+                let suffix = host[1..].to_owned();
+                if !self.wildcards.iter().any(|(s, _)| s == &suffix) {
+                    self.wildcards.push((suffix.clone(), PathTree::new()));
+                    self.wildcards
+                        .sort_by_key(|(s, _)| std::cmp::Reverse(s.len()));
+                }
+                &mut self
+                    .wildcards
+                    .iter_mut()
+                    .find(|(s, _)| s == &suffix)
+                    .unwrap()
+                    .1
+            }
+            Some(host) => self.hosts.entry(host).or_default(),
+        };
+        self.balancers.push(target.route.balancer.clone());
+        routes.insert(&path, target);
+    }
+
+    // Balancers of all routes
+    pub fn balancers(&self) -> &[Arc<Balancer>] {
+        &self.balancers
+    }
+
+    // All distinct upstreams of all routes
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+
+    pub fn trusted_proxies(&self) -> &TrustedProxies {
+        &self.trusted_proxies
+    }
+
+    pub fn security_headers(&self) -> Option<&SecurityHeaders> {
+        self.security_headers.as_ref()
+    }
+
+    // Selects the routes of the requested host, exact names are preferred
+    // over wildcards and routes without a host act as fallback
+    fn routes_for(&self, req: &Request<Body>) -> &PathTree<Target> {
+        if let Some(host) = request_host(req) {
+            if let Some(routes) = self.hosts.get(&host) {
+                return routes;
+            }
+            if let Some((_, routes)) = self
+                .wildcards
+                .iter()
+                .find(|(suffix, _)| host_matches_wildcard(suffix, &host))
+            {
+                return routes;
+            }
+        }
+        &self.default
     }
 
-    pub fn eval(&self, req: &Request<Body>) -> RouterResult {
-        if let Some(node) = self.routes.find(req.uri().path()) {
+    // The client address and credentials are checked before the method,
+    // rejected clients learn nothing about the route
+    pub fn eval(&self, req: &Request<Body>, client_ip: IpAddr) -> RouterResult {
+        let allows = |acl: &Option<IpAcl>| acl.as_ref().is_none_or(|acl| acl.allows(client_ip));
+        if !allows(&self.ip_acl) {
+            return RouterResult::Denied;
+        }
+        if let Some(node) = self.routes_for(req).find(req.uri().path()) {
             let target = node.0;
-            if target.allowed_methods == AllowedMethods::Any
-                || target.allowed_methods.contains(&req.method())
+            let check = target
+                .basic_auth
+                .as_ref()
+                .map(|auth| (auth, auth.check(req.headers())));
+            if !allows(&target.ip_acl) {
+                RouterResult::Denied
+            } else if let Some((auth, Check::Rejected)) = check {
+                RouterResult::Unauthorized(auth.challenge())
+            } else if let Some((auth, Check::Unverified)) = check {
+                RouterResult::Unverified(auth.clone())
+            } else if target.allowed_methods == AllowedMethods::Any
+                || target.allowed_methods.contains(req.method())
             {
-                let uri = Uri::builder().scheme(Scheme::HTTP);
-                let uri = uri.authority(
-                    Authority::from_str(&format!("{}:{}", target.addr.ip(), target.addr.port()))
-                        .unwrap(),
-                );
                 let params = node
                     .1
                     .iter()
@@ -83,9 +318,10 @@ impl Router {
                 } else {
                     String::default()
                 };
-                let p_and_q: &str = &p_and_q;
-                let uri = uri.path_and_query(p_and_q).build().unwrap();
-                RouterResult::Success(uri)
+                RouterResult::Success(Forward {
+                    route: target.route.clone(),
+                    path_and_query: p_and_q,
+                })
             } else {
                 RouterResult::NotAllowedMethod
             }
@@ -96,9 +332,7 @@ impl Router {
 
     #[cfg(test)]
     pub fn new() -> Self {
-        Self {
-            routes: PathTree::new(),
-        }
+        Self::empty()
     }
 
     #[cfg(test)]
@@ -109,12 +343,47 @@ impl Router {
         allowed_methods: AllowedMethods,
         path: Option<String>,
     ) {
-        self.routes.insert(
-            &make_path(source.to_owned()),
+        self.add_host_route(None, source, addr, allowed_methods, path);
+    }
+
+    #[cfg(test)]
+    pub fn add_host_route(
+        &mut self,
+        host: Option<&str>,
+        source: &str,
+        addr: SocketAddr,
+        allowed_methods: AllowedMethods,
+        path: Option<String>,
+    ) {
+        let backend = Backend::new(self.upstream(addr, &Default::default(), None, None), 1);
+        let balancer = Balancer::new(
+            source.to_owned(),
+            vec![backend],
+            Default::default(),
+            None,
+            None,
+        )
+        .unwrap();
+        self.insert(
+            host,
+            source,
             Target {
-                addr,
+                route: Arc::new(Route {
+                    balancer: Arc::new(balancer),
+                    response_timeout: None,
+                    body_timeout: None,
+                    retry: None,
+                    upgrade: false,
+                    upgrade_idle: None,
+                    request_headers: None,
+                    response_headers: None,
+                    security_headers: None,
+                    strip_authorization: false,
+                }),
                 path,
                 allowed_methods,
+                ip_acl: None,
+                basic_auth: None,
             },
         );
     }
@@ -123,7 +392,9 @@ impl Router {
 #[cfg(test)]
 mod tests {
     use super::{make_path, AllowedMethods, Router, RouterResult};
+    use crate::config::Config;
     use hyper::{Body, Method, Request, Uri};
+    use std::net::IpAddr;
 
     fn build_req(uri: &str, method: hyper::Method) -> Request<Body> {
         Request::builder()
@@ -133,6 +404,28 @@ mod tests {
             .unwrap()
     }
 
+    fn client() -> IpAddr {
+        "192.168.0.1".parse().unwrap()
+    }
+
+    fn success(uri: &'static str) -> Option<Uri> {
+        Some(Uri::from_static(uri))
+    }
+
+    fn forward_uri(result: RouterResult) -> Option<Uri> {
+        match result {
+            RouterResult::Success(forward) => {
+                let peer = "127.0.0.1".parse().unwrap();
+                let upstream = forward
+                    .route
+                    .balancer
+                    .select(&Request::default(), peer, &[])?;
+                Some(forward.uri(&upstream))
+            }
+            _ => None,
+        }
+    }
+
     #[test]
     fn config_path() {
         assert_eq!(make_path("/".to_owned()), "/".to_owned());
@@ -162,40 +455,190 @@ mod tests {
         router.add_route("/site/:name", site, AllowedMethods::Any, None);
 
         assert_eq!(
-            router.eval(&build_req("/", Method::GET)),
-            RouterResult::Success(Uri::from_static("http://0.0.0.0:8080"))
+            forward_uri(router.eval(&build_req("/", Method::GET), client())),
+            success("http://0.0.0.0:8080")
         );
         assert_eq!(
-            router.eval(&build_req("/home", Method::GET)),
-            RouterResult::Success(Uri::from_static("http://0.0.0.0:8000"))
+            forward_uri(router.eval(&build_req("/home", Method::GET), client())),
+            success("http://0.0.0.0:8000")
         );
         assert_eq!(
-            router.eval(&build_req("/home?asdf=foobar", Method::GET)),
-            RouterResult::Success(Uri::from_static("http://0.0.0.0:8000/?asdf=foobar"))
+            forward_uri(router.eval(&build_req("/home?asdf=foobar", Method::GET), client())),
+            success("http://0.0.0.0:8000/?asdf=foobar")
+        );
+        assert!(matches!(
+            router.eval(&build_req("/home/asdf", Method::GET), client()),
+            RouterResult::NotDefined
+        ));
+        assert_eq!(
+            forward_uri(router.eval(&build_req("/bulk/qwerty", Method::GET), client())),
+            success("http://0.0.0.0:3000/qwerty")
         );
         assert_eq!(
-            router.eval(&build_req("/home/asdf", Method::GET)),
+            forward_uri(router.eval(&build_req("/bulk/asdf/qwerty", Method::GET), client())),
+            success("http://0.0.0.0:3000/asdf/qwerty")
+        );
+        assert!(matches!(
+            router.eval(&build_req("/home", Method::POST), client()),
+            RouterResult::NotAllowedMethod
+        ));
+        assert_eq!(
+            forward_uri(router.eval(&build_req("/specific", Method::GET), client())),
+            success("http://0.0.0.0:7000/foobar")
+        );
+        assert!(matches!(
+            router.eval(&build_req("/notdefined", Method::GET), client()),
             RouterResult::NotDefined
+        ));
+    }
+
+    #[test]
+    fn host_evaluation() {
+        let fallback = "0.0.0.0:8080".parse().unwrap();
+        let exact = "0.0.0.0:8000".parse().unwrap();
+        let wildcard = "0.0.0.0:7000".parse().unwrap();
+        let nested = "0.0.0.0:6000".parse().unwrap();
+        let mut router = Router::new();
+        router.add_route("/", fallback, AllowedMethods::Any, None);
+        router.add_host_route(
+            Some("www.example.com"),
+            "/",
+            exact,
+            AllowedMethods::Any,
+            None,
+        );
+        router.add_host_route(
+            Some("*.example.com"),
+            "/",
+            wildcard,
+            AllowedMethods::Any,
+            None,
         );
+        router.add_host_route(
+            Some("*.api.Example.com"),
+            "/v1",
+            nested,
+            AllowedMethods::Any,
+            None,
+        );
+
+        let with_host = |uri: &str, host: &str| {
+            let mut req = build_req(uri, Method::GET);
+            req.headers_mut().insert("host", host.parse().unwrap());
+            req
+        };
+
         assert_eq!(
-            router.eval(&build_req("/bulk/qwerty", Method::GET)),
-            RouterResult::Success(Uri::from_static("http://0.0.0.0:3000/qwerty"))
+            forward_uri(router.eval(&build_req("/", Method::GET), client())),
+            success("http://0.0.0.0:8080")
         );
         assert_eq!(
-            router.eval(&build_req("/bulk/asdf/qwerty", Method::GET)),
-            RouterResult::Success(Uri::from_static("http://0.0.0.0:3000/asdf/qwerty"))
+            forward_uri(router.eval(&with_host("/", "www.example.com"), client())),
+            success("http://0.0.0.0:8000")
         );
         assert_eq!(
-            router.eval(&build_req("/home", Method::POST)),
-            RouterResult::NotAllowedMethod
+            forward_uri(router.eval(&with_host("/", "WWW.example.com:443"), client())),
+            success("http://0.0.0.0:8000")
         );
         assert_eq!(
-            router.eval(&build_req("/specific", Method::GET)),
-            RouterResult::Success(Uri::from_static("http://0.0.0.0:7000/foobar"))
+            forward_uri(router.eval(
+                &build_req("https://www.example.com/", Method::GET),
+                client()
+            )),
+            success("http://0.0.0.0:8000")
         );
         assert_eq!(
-            router.eval(&build_req("/notdefined", Method::GET)),
+            forward_uri(router.eval(&with_host("/", "shop.example.com"), client())),
+            success("http://0.0.0.0:7000")
+        );
+        assert_eq!(
+            forward_uri(router.eval(&with_host("/v1", "eu.api.example.com"), client())),
+            success("http://0.0.0.0:6000")
+        );
+        assert!(matches!(
+            router.eval(&with_host("/", "eu.api.example.com"), client()),
             RouterResult::NotDefined
+        ));
+        assert_eq!(
+            forward_uri(router.eval(&with_host("/", "example.com"), client())),
+            success("http://0.0.0.0:8080")
+        );
+        assert_eq!(
+            forward_uri(router.eval(&with_host("/", "www.example.org"), client())),
+            success("http://0.0.0.0:8080")
         );
     }
+
+    #[test]
+    fn access_control() {
+        let config: Config = toml::from_str(
+            r#"
+            redirect_to_https = false
+
+            [ip_acl]
+            deny = ['203.0.113.0/24']
+
+            [[routes]]
+            source = '/'
+            target = '127.0.0.1:8000'
+            allowed_methods = []
+
+            [[routes]]
+            source = '/admin'
+            target = '127.0.0.1:9000'
+            allowed_methods = ['GET']
+            ip_acl = { allow = ['10.8.0.0/16', 'fd00::/8'] }
+
+            [[routes]]
+            source = '/tools'
+            target = '127.0.0.1:9100'
+            allowed_methods = ['GET']
+            basic_auth = { htpasswd = '/nonexistent/htpasswd', realm = 'Tools' }
+            "#,
+        )
+        .unwrap();
+        let router = Router::from_config(config).unwrap();
+        let eval = |uri: &str, method: Method, ip: &str| {
+            router.eval(&build_req(uri, method), ip.parse().unwrap())
+        };
+
+        assert!(matches!(
+            eval("/", Method::GET, "192.168.0.1"),
+            RouterResult::Success(_)
+        ));
+        assert!(matches!(
+            eval("/", Method::GET, "203.0.113.7"),
+            RouterResult::Denied
+        ));
+        assert!(matches!(
+            eval("/notdefined", Method::GET, "203.0.113.7"),
+            RouterResult::Denied
+        ));
+        assert!(matches!(
+            eval("/admin", Method::GET, "10.8.3.4"),
+            RouterResult::Success(_)
+        ));
+        assert!(matches!(
+            eval("/admin", Method::GET, "fd00::2"),
+            RouterResult::Success(_)
+        ));
+        assert!(matches!(
+            eval("/admin", Method::GET, "192.168.0.1"),
+            RouterResult::Denied
+        ));
+        assert!(matches!(
+            eval("/admin", Method::POST, "192.168.0.1"),
+            RouterResult::Denied
+        ));
+        assert!(matches!(
+            eval("/admin", Method::POST, "10.8.3.4"),
+            RouterResult::NotAllowedMethod
+        ));
+        match eval("/tools", Method::POST, "192.168.0.1") {
+            RouterResult::Unauthorized(challenge) => {
+                assert_eq!(challenge, "Basic realm=\"Tools\", charset=\"UTF-8\"")
+            }
+            _ => panic!("expected a challenge"),
+        }
+    }
 }
@@ -0,0 +1,174 @@
+use crate::config::SecurityHeadersDefinition;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY,
+    STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use log::warn;
+
+const DEFAULT_HSTS_MAX_AGE_SECS: u64 = 365 * 24 * 60 * 60;
+const DEFAULT_CONTENT_TYPE_OPTIONS: &str = "nosniff";
+const DEFAULT_FRAME_OPTIONS: &str = "DENY";
+const DEFAULT_REFERRER_POLICY: &str = "strict-origin-when-cross-origin";
+
+// Headers added to every response sent to clients, replacing the values
+// sent by the backends
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl SecurityHeaders {
+    // The settings of a route override the global ones, None if neither
+    // defines a policy
+    pub fn from_config(
+        global: Option<&SecurityHeadersDefinition>,
+        route: Option<&SecurityHeadersDefinition>,
+    ) -> Option<Self> {
+        if global.is_none() && route.is_none() {
+            return None;
+        }
+        let global = global.cloned().unwrap_or_default();
+        let route = route.cloned().unwrap_or_default();
+        let config = SecurityHeadersDefinition {
+            hsts: route.hsts.or(global.hsts),
+            hsts_max_age_secs: route.hsts_max_age_secs.or(global.hsts_max_age_secs),
+            hsts_include_subdomains: route
+                .hsts_include_subdomains
+                .or(global.hsts_include_subdomains),
+            hsts_preload: route.hsts_preload.or(global.hsts_preload),
+            content_type_options: route.content_type_options.or(global.content_type_options),
+            frame_options: route.frame_options.or(global.frame_options),
+            referrer_policy: route.referrer_policy.or(global.referrer_policy),
+            content_security_policy: route
+                .content_security_policy
+                .or(global.content_security_policy),
+            permissions_policy: route.permissions_policy.or(global.permissions_policy),
+        };
+
+        let mut headers = vec![];
+        if config.hsts.unwrap_or(true) {
+            let mut hsts = format!(
+                "max-age={}",
+                config
+                    .hsts_max_age_secs
+                    .unwrap_or(DEFAULT_HSTS_MAX_AGE_SECS)
+            );
+            if config.hsts_include_subdomains.unwrap_or(false) {
+                hsts.push_str("; includeSubDomains");
+            }
+            if config.hsts_preload.unwrap_or(false) {
+                hsts.push_str("; preload");
+            }
+            push(&mut headers, STRICT_TRANSPORT_SECURITY, hsts);
+        }
+        let values = vec![
+            (
+                X_CONTENT_TYPE_OPTIONS,
+                config.content_type_options,
+                Some(DEFAULT_CONTENT_TYPE_OPTIONS),
+            ),
+            (
+                X_FRAME_OPTIONS,
+                config.frame_options,
+                Some(DEFAULT_FRAME_OPTIONS),
+            ),
+            (
+                REFERRER_POLICY,
+                config.referrer_policy,
+                Some(DEFAULT_REFERRER_POLICY),
+            ),
+            (
+                CONTENT_SECURITY_POLICY,
+                config.content_security_policy,
+                None,
+            ),
+            (
+                HeaderName::from_static("permissions-policy"),
+                config.permissions_policy,
+                None,
+            ),
+        ];
+        for (name, value, default) in values {
+            if let Some(value) = value.or_else(|| default.map(str::to_owned)) {
+                push(&mut headers, name, value);
+            }
+        }
+        Some(Self { headers })
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap<HeaderValue>) {
+        for (name, value) in &self.headers {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+fn push(headers: &mut Vec<(HeaderName, HeaderValue)>, name: HeaderName, value: String) {
+    if value.is_empty() {
+        return;
+    }
+    match HeaderValue::from_str(&value) {
+        Ok(value) => headers.push((name, value)),
+        Err(_) => warn!("Ignoring invalid value '{}' for header {}!", value, name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SecurityHeaders;
+    use crate::config::SecurityHeadersDefinition;
+    use hyper::header::HeaderMap;
+
+    #[test]
+    fn defaults() {
+        assert_eq!(SecurityHeaders::from_config(None, None), None);
+        let security = SecurityHeaders::from_config(Some(&Default::default()), None).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-frame-options", "SAMEORIGIN".parse().unwrap());
+        security.apply(&mut headers);
+        assert_eq!(headers["strict-transport-security"], "max-age=31536000");
+        assert_eq!(headers["x-content-type-options"], "nosniff");
+        assert_eq!(headers["x-frame-options"], "DENY");
+        assert_eq!(
+            headers["referrer-policy"],
+            "strict-origin-when-cross-origin"
+        );
+        assert!(!headers.contains_key("content-security-policy"));
+        assert!(!headers.contains_key("permissions-policy"));
+    }
+
+    #[test]
+    fn route_overrides() {
+        let global = SecurityHeadersDefinition {
+            hsts_max_age_secs: Some(600),
+            hsts_include_subdomains: Some(true),
+            hsts_preload: Some(true),
+            content_security_policy: Some("default-src 'self'".to_owned()),
+            ..Default::default()
+        };
+        let route = SecurityHeadersDefinition {
+            frame_options: Some(String::new()),
+            permissions_policy: Some("camera=()".to_owned()),
+            ..Default::default()
+        };
+        let security = SecurityHeaders::from_config(Some(&global), Some(&route)).unwrap();
+        let mut headers = HeaderMap::new();
+        security.apply(&mut headers);
+        assert_eq!(
+            headers["strict-transport-security"],
+            "max-age=600; includeSubDomains; preload"
+        );
+        assert_eq!(headers["content-security-policy"], "default-src 'self'");
+        assert_eq!(headers["permissions-policy"], "camera=()");
+        assert!(!headers.contains_key("x-frame-options"));
+
+        let no_hsts = SecurityHeadersDefinition {
+            hsts: Some(false),
+            ..Default::default()
+        };
+        let security = SecurityHeaders::from_config(Some(&global), Some(&no_hsts)).unwrap();
+        let mut headers = HeaderMap::new();
+        security.apply(&mut headers);
+        assert!(!headers.contains_key("strict-transport-security"));
+    }
+}
@@ -0,0 +1,88 @@
+use log::{error, info};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+// Notifies the servers once SIGTERM, SIGQUIT or SIGINT was received
+#[derive(Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn listen() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        tokio::spawn(async move {
+            if wait_for_signal().await {
+                let _ = sender.broadcast(true);
+            }
+        });
+        Self { receiver }
+    }
+
+    // Resolves once the shutdown started
+    pub async fn wait(mut self) {
+        while let Some(started) = self.receiver.recv().await {
+            if started {
+                return;
+            }
+        }
+        // The sender is gone if the signal handlers could not be installed
+        futures::future::pending::<()>().await;
+    }
+}
+
+// False if the handlers could not be installed or were removed
+async fn wait_for_signal() -> bool {
+    let kinds = [
+        ("SIGTERM", SignalKind::terminate()),
+        ("SIGQUIT", SignalKind::quit()),
+        ("SIGINT", SignalKind::interrupt()),
+    ];
+    let mut signals = Vec::new();
+    for (name, kind) in kinds.iter() {
+        match signal(*kind) {
+            Ok(signal) => signals.push((*name, signal)),
+            Err(err) => {
+                error!("Could not install {} handler! {}", name, err);
+                return false;
+            }
+        }
+    }
+    let received = futures::future::select_all(
+        signals
+            .iter_mut()
+            .map(|(name, signal)| Box::pin(async move { signal.recv().await.map(|_| *name) })),
+    )
+    .await
+    .0;
+    match received {
+        Some(name) => {
+            info!("Received {}, shutting down", name);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shutdown;
+    use std::time::Duration;
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn wait() {
+        let (sender, receiver) = watch::channel(false);
+        let shutdown = Shutdown { receiver };
+        let waiting = tokio::spawn(shutdown.clone().wait());
+        let early = tokio::time::timeout(Duration::from_millis(50), shutdown.clone().wait());
+        assert!(early.await.is_err());
+
+        sender.broadcast(true).unwrap();
+        tokio::time::timeout(Duration::from_millis(500), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        shutdown.wait().await;
+    }
+}
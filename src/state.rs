@@ -0,0 +1,642 @@
+use crate::access_log::AccessLog;
+use crate::config::{self, AccessLogDefinition, Config, ConfigError, RouteDefinition};
+use crate::health;
+use crate::listener::Listeners;
+use crate::metrics::Metrics;
+use crate::router::Router;
+use crate::systemd::Notifier;
+use crate::tls::{self, ReloadableResolver};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use log::{debug, error, info, warn};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+
+const DEFAULT_CERT_CHECK_INTERVAL_SECS: u64 = 60;
+const POOL_STATS_INTERVAL_SECS: u64 = 60;
+
+// Runtime state which is swapped atomically on reload
+// Requests in flight keep the router they started with
+pub struct State {
+    file: String,
+    config: ArcSwap<Config>,
+    router: ArcSwap<Router>,
+    certs: Arc<ReloadableResolver>,
+    access_log: ArcSwapOption<AccessLog>,
+    metrics: Arc<Metrics>,
+    // Upstreams disabled through the admin API, kept across reloads
+    disabled: Mutex<HashSet<SocketAddr>>,
+    notifier: Arc<Notifier>,
+    // Files are not accessible by their configured paths anymore
+    chrooted: bool,
+}
+
+impl State {
+    pub fn new(
+        file: String,
+        config: Config,
+        certs: Arc<ReloadableResolver>,
+        notifier: Arc<Notifier>,
+    ) -> Result<Self, ConfigError> {
+        let router = Router::from_config(config.clone())?;
+        Ok(Self {
+            chrooted: config.chroot.is_some(),
+            file,
+            router: ArcSwap::from_pointee(activate(router, None, &HashSet::new())),
+            access_log: ArcSwapOption::new(
+                config
+                    .access_log
+                    .as_ref()
+                    .and_then(open_access_log)
+                    .map(Arc::new),
+            ),
+            config: ArcSwap::from_pointee(config),
+            certs,
+            metrics: Arc::new(Metrics::default()),
+            disabled: Mutex::new(HashSet::new()),
+            notifier,
+        })
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.load_full()
+    }
+
+    pub fn router(&self) -> Arc<Router> {
+        self.router.load_full()
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn access_log(&self) -> Option<Arc<AccessLog>> {
+        self.access_log.load_full()
+    }
+
+    // Called on SIGUSR1 after the log file was rotated
+    pub fn reopen_access_log(&self) {
+        if let Some(access_log) = self.access_log() {
+            if self.chrooted {
+                warn!(
+                    "The access log cannot be reopened inside a chroot, restart heimdall instead!"
+                );
+                return;
+            }
+            match access_log.reopen() {
+                Ok(()) => info!("Reopened access log"),
+                Err(err) => error!("Could not reopen access log! {}", err),
+            }
+        }
+    }
+
+    pub fn reload_certificates(&self) {
+        if self.chrooted {
+            warn!("Certificates cannot be reloaded inside a chroot, restart heimdall instead!");
+            return;
+        }
+        self.certs.reload(&self.config());
+    }
+
+    // Files read on reloads which the unprivileged user cannot open, the
+    // previous config and certificates would be kept
+    pub fn warn_unreadable(&self) {
+        let config = self.config();
+        let mut files = vec![self.file.clone()];
+        files.extend(tls::modification_times(&config).into_keys());
+        files.extend(
+            config
+                .routes
+                .iter()
+                .filter_map(|route| route.basic_auth.as_ref())
+                .map(|auth| auth.htpasswd.clone()),
+        );
+        for file in files {
+            if let Err(err) = std::fs::File::open(&file) {
+                warn!(
+                    "'{}' cannot be read after dropping privileges, reloads will fail! {}",
+                    file, err
+                );
+            }
+        }
+        // Reopening on SIGUSR1 needs write access
+        let path = config
+            .access_log
+            .as_ref()
+            .and_then(|log| log.path.as_deref());
+        if let Some(path) = path.filter(|path| *path != "-") {
+            if let Err(err) = std::fs::OpenOptions::new().append(true).open(path) {
+                warn!(
+                    "'{}' cannot be written after dropping privileges, reopening the access log will fail! {}",
+                    path, err
+                );
+            }
+        }
+    }
+
+    pub fn certificate_expiries(&self) -> Vec<(String, Option<SystemTime>)> {
+        self.certs.expiries()
+    }
+
+    // Stops or resumes sending new requests to the upstreams with the
+    // address, false if no route uses it
+    pub fn set_disabled(&self, addr: SocketAddr, disabled: bool) -> bool {
+        let mut addrs = self.disabled.lock().unwrap();
+        let router = self.router();
+        let upstreams: Vec<_> = router
+            .upstreams()
+            .iter()
+            .filter(|upstream| upstream.addr() == addr)
+            .collect();
+        if upstreams.is_empty() {
+            return false;
+        }
+        for upstream in upstreams {
+            upstream.set_disabled(disabled);
+        }
+        if disabled {
+            info!("Disabled upstream {}", addr);
+            addrs.insert(addr);
+        } else {
+            info!("Enabled upstream {}", addr);
+            addrs.remove(&addr);
+        }
+        true
+    }
+
+    // Reloads the config file, the previous config stays active on errors
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        if self.chrooted {
+            return Err(ConfigError::Chrooted);
+        }
+        self.notifier.reloading();
+        let result = self.load();
+        self.notifier.reloaded();
+        result
+    }
+
+    fn load(&self) -> Result<(), ConfigError> {
+        let config = config::load(&self.file)?;
+        // Nothing is changed if the routes are invalid
+        let router = Router::from_config(config.clone())?;
+        let previous = self.config();
+        if Listeners::from_config(&config) != Listeners::from_config(&previous) {
+            warn!("Changing listeners requires a restart, keeping the previous ones");
+        }
+        let socket = |config: &Config| config.admin.as_ref().and_then(|admin| admin.socket.clone());
+        if socket(&config) != socket(&previous) {
+            warn!("Changing the admin socket requires a restart, keeping the previous one");
+        }
+        log_route_changes(&previous.routes, &config.routes);
+        // The open log file is kept unless its settings changed
+        if config.access_log != previous.access_log {
+            let access_log = config.access_log.as_ref().and_then(open_access_log);
+            if config.access_log.is_none() || access_log.is_some() {
+                self.access_log.store(access_log.map(Arc::new));
+            }
+        }
+        // Held until the router is stored so no upstream is disabled on the
+        // replaced router only
+        let disabled = self.disabled.lock().unwrap();
+        let previous_router = self.router();
+        self.router.store(Arc::new(activate(
+            router,
+            Some(&previous_router),
+            &disabled,
+        )));
+        drop(disabled);
+        self.certs.reload(&config);
+        self.config.store(Arc::new(config));
+        info!("Reloaded config from '{}'", self.file);
+        Ok(())
+    }
+}
+
+fn open_access_log(config: &AccessLogDefinition) -> Option<AccessLog> {
+    match AccessLog::from_config(config) {
+        Ok(access_log) => Some(access_log),
+        Err(err) => {
+            error!("Could not open access log! {}", err);
+            None
+        }
+    }
+}
+
+// Applies the disabled upstreams, takes over the health of the backends
+// and the circuit breakers of the previous router and starts the health
+// checks, which end once the router is replaced and no request uses it
+// anymore
+fn activate(router: Router, previous: Option<&Router>, disabled: &HashSet<SocketAddr>) -> Router {
+    for upstream in router.upstreams() {
+        upstream.set_disabled(disabled.contains(&upstream.addr()));
+    }
+    if let Some(previous) = previous {
+        restore_health(&router, previous);
+        restore_breakers(&router, previous);
+        restore_pool_stats(&router, previous);
+    }
+    health::spawn_checks(router.balancers());
+    router
+}
+
+// Open breakers of failing upstreams stay open, matched by address
+fn restore_breakers(router: &Router, previous: &Router) {
+    for upstream in router.upstreams() {
+        let breaker = match upstream.breaker() {
+            Some(breaker) => breaker,
+            None => continue,
+        };
+        if let Some(old) = previous
+            .upstreams()
+            .iter()
+            .filter(|old| old.addr() == upstream.addr())
+            .find_map(|old| old.breaker())
+        {
+            breaker.restore(old);
+        }
+    }
+}
+
+// The pool counters are exported as metrics and must not start over
+fn restore_pool_stats(router: &Router, previous: &Router) {
+    for upstream in router.upstreams() {
+        if let Some(old) = previous
+            .upstreams()
+            .iter()
+            .find(|old| old.addr() == upstream.addr())
+        {
+            upstream.stats().restore(old.stats());
+        }
+    }
+}
+
+// Backends known to be down stay down until their checks succeed again,
+// matched by route and address. Without a health check nothing would
+// mark them healthy again.
+fn restore_health(router: &Router, previous: &Router) {
+    for balancer in router.balancers() {
+        if balancer.health_check().is_none() {
+            continue;
+        }
+        let previous = match previous
+            .balancers()
+            .iter()
+            .find(|p| p.route() == balancer.route() && p.health_check().is_some())
+        {
+            Some(previous) => previous,
+            None => continue,
+        };
+        for backend in balancer.backends() {
+            let addr = backend.upstream.addr();
+            if let Some(old) = previous
+                .backends()
+                .iter()
+                .find(|b| b.upstream.addr() == addr)
+            {
+                backend.health.restore(&old.health);
+            }
+        }
+    }
+}
+
+// Reloads the config on SIGHUP, the certificates when their files change and
+// reopens the access log on SIGUSR1
+pub async fn watch(state: Arc<State>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            error!("Could not install SIGHUP handler! {}", err);
+            return;
+        }
+    };
+    let mut user_defined1 = match signal(SignalKind::user_defined1()) {
+        Ok(user_defined1) => user_defined1,
+        Err(err) => {
+            error!("Could not install SIGUSR1 handler! {}", err);
+            return;
+        }
+    };
+    let mut secs = cert_check_interval(&state);
+    let mut interval = tokio::time::interval(Duration::from_secs(secs.max(1)));
+    let mut modified = tls::modification_times(&state.config());
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading config");
+                if let Err(err) = state.reload() {
+                    error!("Could not reload config, keeping previous one! {}", err);
+                }
+            }
+            _ = user_defined1.recv() => {
+                state.reopen_access_log();
+            }
+            _ = interval.tick(), if secs > 0 => {
+                let current = tls::modification_times(&state.config());
+                if current != modified {
+                    info!("Certificate files changed, reloading certificates");
+                    state.reload_certificates();
+                }
+            }
+        }
+        modified = tls::modification_times(&state.config());
+        // A reload may have changed the interval
+        let current = cert_check_interval(&state);
+        if current != secs {
+            info!("Checking the certificate files every {} seconds", current);
+            secs = current;
+            interval = tokio::time::interval(Duration::from_secs(secs.max(1)));
+        }
+    }
+}
+
+// Seconds between the checks for changed certificate files, 0 disables them.
+// The certificate files are outside of the chroot.
+fn cert_check_interval(state: &State) -> u64 {
+    if state.chrooted {
+        0
+    } else {
+        state
+            .config()
+            .cert_check_interval_secs
+            .unwrap_or(DEFAULT_CERT_CHECK_INTERVAL_SECS)
+    }
+}
+
+// Logs the connection pool usage of all upstreams
+pub async fn report_pool_stats(state: Arc<State>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(POOL_STATS_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        for upstream in state.router().upstreams() {
+            let stats = upstream.stats();
+            debug!(
+                "Upstream {}: {} requests, {} connections opened, {} reused",
+                upstream.addr(),
+                stats.requests(),
+                stats.connections(),
+                stats.reused()
+            );
+        }
+    }
+}
+
+fn route_key(route: &RouteDefinition) -> String {
+    format!("{}{}", route.host.as_deref().unwrap_or(""), route.source)
+}
+
+// Returns the keys of added, removed and changed routes
+fn diff_routes(
+    previous: &[RouteDefinition],
+    current: &[RouteDefinition],
+) -> (Vec<String>, Vec<String>, Vec<String>) {
+    let find = |routes: &[RouteDefinition], key: &str| {
+        routes.iter().find(|r| route_key(r) == key).cloned()
+    };
+    let mut added = vec![];
+    let mut changed = vec![];
+    for route in current {
+        let key = route_key(route);
+        match find(previous, &key) {
+            None => added.push(key),
+            Some(old) if &old != route => changed.push(key),
+            Some(_) => {}
+        }
+    }
+    let removed = previous
+        .iter()
+        .map(route_key)
+        .filter(|key| find(current, key).is_none())
+        .collect();
+    (added, removed, changed)
+}
+
+fn log_route_changes(previous: &[RouteDefinition], current: &[RouteDefinition]) {
+    let (added, removed, changed) = diff_routes(previous, current);
+    for key in added {
+        info!("Route added: {}", key);
+    }
+    for key in removed {
+        info!("Route removed: {}", key);
+    }
+    for key in changed {
+        info!("Route changed: {}", key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cert_check_interval, diff_routes, State, DEFAULT_CERT_CHECK_INTERVAL_SECS};
+    use crate::acme::Challenges;
+    use crate::breaker::Outcome;
+    use crate::config::{Config, ConfigError, RouteDefinition, TargetDefinition};
+    use crate::router::RouterResult;
+    use crate::systemd::Notifier;
+    use crate::tls::{self, ReloadableResolver};
+    use hyper::{Body, Request};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn route(host: Option<&str>, source: &str, target: &str) -> RouteDefinition {
+        RouteDefinition {
+            host: host.map(|h| h.to_owned()),
+            source: source.to_owned(),
+            target: TargetDefinition::Single(target.parse().unwrap()),
+            target_path: None,
+            allowed_methods: vec![],
+            balance: None,
+            hash_header: None,
+            upgrade: None,
+            health_check: None,
+            timeouts: None,
+            retry: None,
+            request_headers: None,
+            response_headers: None,
+            security_headers: None,
+            ip_acl: None,
+            basic_auth: None,
+        }
+    }
+
+    #[test]
+    fn route_changes() {
+        let previous = vec![
+            route(None, "/", "127.0.0.1:8000"),
+            route(None, "/stuff", "127.0.0.1:7000"),
+            route(Some("www.foo.bar"), "/", "127.0.0.1:6000"),
+        ];
+        let current = vec![
+            route(None, "/", "127.0.0.1:8000"),
+            route(None, "/stuff", "127.0.0.1:7001"),
+            route(Some("api.foo.bar"), "/", "127.0.0.1:5000"),
+        ];
+        let (added, removed, changed) = diff_routes(&previous, &current);
+        assert_eq!(added, vec!["api.foo.bar/".to_owned()]);
+        assert_eq!(removed, vec!["www.foo.bar/".to_owned()]);
+        assert_eq!(changed, vec!["/stuff".to_owned()]);
+
+        let (added, removed, changed) = diff_routes(&previous, &previous);
+        assert!(added.is_empty() && removed.is_empty() && changed.is_empty());
+    }
+
+    // Config file with a test certificate and the given routes
+    struct TestConfig {
+        file: PathBuf,
+        cert_file: String,
+        pkey_file: String,
+    }
+
+    impl TestConfig {
+        fn new(name: &str) -> Self {
+            let (cert_file, pkey_file) = tls::write_test_certificate(name, &["localhost"]);
+            let file =
+                std::env::temp_dir().join(format!("heimdall-{}-{}.toml", name, std::process::id()));
+            Self {
+                file,
+                cert_file,
+                pkey_file,
+            }
+        }
+
+        fn write(&self, routes: &str) {
+            let config = format!(
+                "cert_file = '{}'\npkey_file = '{}'\nredirect_to_https = false\n{}",
+                self.cert_file, self.pkey_file, routes
+            );
+            std::fs::write(&self.file, config).unwrap();
+        }
+
+        fn load(&self) -> Config {
+            crate::config::load(self.file.to_str().unwrap()).unwrap()
+        }
+
+        fn state(&self, config: Config) -> State {
+            let resolver =
+                ReloadableResolver::from_config(&config, Arc::new(Challenges::default()));
+            State::new(
+                self.file.to_str().unwrap().to_owned(),
+                config,
+                Arc::new(resolver.unwrap()),
+                Arc::new(Notifier::default()),
+            )
+            .unwrap()
+        }
+    }
+
+    impl Drop for TestConfig {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.file);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_reload() {
+        let config = TestConfig::new("state");
+        config.write("[[routes]]\nsource = '/'\ntarget = '127.0.0.1:8000'\nallowed_methods = []");
+        let state = config.state(config.load());
+        let routes = |state: &State| {
+            let request = Request::get("/").body(Body::empty()).unwrap();
+            match state.router().eval(&request, "127.0.0.1".parse().unwrap()) {
+                RouterResult::Success(forward) => forward.route.balancer.route().to_owned(),
+                _ => "none".to_owned(),
+            }
+        };
+        assert_eq!(routes(&state), "/");
+        let router = state.router();
+
+        for invalid in &[
+            "[[routes]]\nsource = '/'\ntarget = []\nallowed_methods = []",
+            "[[routes]]\nsource = '/'\ntarget = '127.0.0.1:8000'\nallowed_methods = ['FETCH']",
+            "[[routes]]\nsource = '/'\ntarget = '127.0.0.1:8000'\nallowed_methods = []\nretry = { methods = ['FETCH'] }",
+        ] {
+            config.write(invalid);
+            assert!(state.reload().is_err());
+            assert!(Arc::ptr_eq(&router, &state.router()));
+        }
+        // The admin API keeps working
+        assert!(state.set_disabled("127.0.0.1:8000".parse().unwrap(), true));
+        assert!(state.set_disabled("127.0.0.1:8000".parse().unwrap(), false));
+
+        config.write("[[routes]]\nsource = '/'\ntarget = '127.0.0.1:8001'\nallowed_methods = []");
+        state.reload().unwrap();
+        assert!(!Arc::ptr_eq(&router, &state.router()));
+
+        // The config file is outside of the chroot
+        let mut chroot_config = config.load();
+        chroot_config.chroot = Some("/var/lib/heimdall".to_owned());
+        let chrooted = config.state(chroot_config);
+        assert!(matches!(chrooted.reload(), Err(ConfigError::Chrooted)));
+    }
+
+    #[tokio::test]
+    async fn reload_cert_check_interval() {
+        let config = TestConfig::new("interval");
+        let routes = "[[routes]]\nsource = '/'\ntarget = '127.0.0.1:8000'\nallowed_methods = []";
+        config.write(routes);
+        let state = config.state(config.load());
+        assert_eq!(
+            cert_check_interval(&state),
+            DEFAULT_CERT_CHECK_INTERVAL_SECS
+        );
+
+        config.write(&format!("cert_check_interval_secs = 5\n{}", routes));
+        state.reload().unwrap();
+        assert_eq!(cert_check_interval(&state), 5);
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_breakers() {
+        let config = TestConfig::new("state-breaker");
+        let routes = "circuit_breaker = { consecutive_failures = 1 }\n\
+                      [[routes]]\nsource = '/'\ntarget = '127.0.0.1:8000'\nallowed_methods = []\n";
+        config.write(routes);
+        let state = config.state(config.load());
+        let breaker_state =
+            |state: &State| state.router().upstreams()[0].breaker().unwrap().state();
+        let router = state.router();
+        let breaker = router.upstreams()[0].breaker().unwrap();
+        let ticket = breaker.start().unwrap();
+        breaker.record(ticket, Outcome::Failure);
+        assert_eq!(breaker_state(&state), "open");
+
+        config.write(&format!(
+            "{}[[routes]]\nsource = '/other'\ntarget = '127.0.0.1:9000'\nallowed_methods = []",
+            routes
+        ));
+        state.reload().unwrap();
+        assert_eq!(breaker_state(&state), "open");
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_health() {
+        let config = TestConfig::new("state-health");
+        let route = "[[routes]]\nsource = '/'\ntarget = ['127.0.0.1:8000', '127.0.0.1:8001']\n\
+                     allowed_methods = []\nhealth_check = { path = '/', interval_secs = 3600 }\n";
+        config.write(route);
+        let state = config.state(config.load());
+        let health = |state: &State| -> Vec<bool> {
+            state.router().balancers()[0]
+                .backends()
+                .iter()
+                .map(|backend| backend.health.is_healthy())
+                .collect()
+        };
+        state.router().balancers()[0].backends()[1]
+            .health
+            .set_healthy(false);
+
+        // Changing an unrelated route keeps the backend down
+        config.write(&format!(
+            "{}[[routes]]\nsource = '/other'\ntarget = '127.0.0.1:9000'\nallowed_methods = []",
+            route
+        ));
+        state.reload().unwrap();
+        assert_eq!(health(&state), vec![true, false]);
+
+        // Without a health check nothing could mark it healthy again
+        config.write("[[routes]]\nsource = '/'\ntarget = ['127.0.0.1:8000', '127.0.0.1:8001']\nallowed_methods = []");
+        state.reload().unwrap();
+        assert_eq!(health(&state), vec![true, true]);
+    }
+}
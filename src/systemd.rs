@@ -0,0 +1,290 @@
+use log::{debug, info, warn};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use std::time::Duration;
+
+// First file descriptor passed by socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+// Number of passed file descriptors, only if they are meant for this process
+fn listen_fds(pid: u32, listen_pid: Option<&str>, listen_fds: Option<&str>) -> RawFd {
+    if listen_pid.and_then(|listen_pid| listen_pid.parse().ok()) != Some(pid) {
+        return 0;
+    }
+    listen_fds.and_then(|count| count.parse().ok()).unwrap_or(0)
+}
+
+// Socket inherited from systemd, tokio expects it to be non-blocking
+fn inherit(listener: TcpListener) -> io::Result<(SocketAddr, TcpListener)> {
+    let addr = listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    Ok((addr, listener))
+}
+
+// Sockets bound by systemd socket activation, used for the listeners with
+// the same address instead of binding them again
+#[derive(Default)]
+pub struct Activated {
+    listeners: Vec<(SocketAddr, TcpListener)>,
+}
+
+impl Activated {
+    pub fn from_env() -> Self {
+        let count = listen_fds(
+            std::process::id(),
+            env::var("LISTEN_PID").ok().as_deref(),
+            env::var("LISTEN_FDS").ok().as_deref(),
+        );
+        // Processes started by heimdall must not take the sockets
+        for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(name);
+        }
+        let mut activated = Self::default();
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            // Ownership of the descriptors is passed on to this process
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            match inherit(listener) {
+                Ok((addr, listener)) => {
+                    info!("Received socket {} from systemd", addr);
+                    activated.listeners.push((addr, listener));
+                }
+                Err(err) => warn!("Ignoring socket {} from systemd! {}", fd, err),
+            }
+        }
+        activated
+    }
+
+    pub fn take(&mut self, addr: SocketAddr) -> Option<TcpListener> {
+        let index = self
+            .listeners
+            .iter()
+            .position(|(bound, _)| *bound == addr)?;
+        Some(self.listeners.remove(index).1)
+    }
+
+    // Closes the sockets which do not match a configured listener
+    pub fn close_unused(self) {
+        for (addr, _) in self.listeners {
+            warn!("Closing socket {} from systemd, no listener uses it!", addr);
+        }
+    }
+}
+
+// Sends state changes to the service manager if NOTIFY_SOCKET is set. The
+// socket is connected right away so that it still works after a chroot.
+#[derive(Default)]
+pub struct Notifier {
+    socket: Option<(UnixDatagram, String)>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        match env::var_os("NOTIFY_SOCKET") {
+            Some(path) => Self::new(path),
+            None => Self::default(),
+        }
+    }
+
+    fn new(path: OsString) -> Self {
+        let name = path.to_string_lossy().into_owned();
+        match connect(path.as_bytes()) {
+            Ok(socket) => Self {
+                socket: Some((socket, name)),
+            },
+            Err(err) => {
+                warn!("Could not connect to notify socket '{}'! {}", name, err);
+                Self::default()
+            }
+        }
+    }
+
+    fn send(&self, state: &str) {
+        if let Some((socket, name)) = &self.socket {
+            if let Err(err) = socket.send(state.as_bytes()) {
+                warn!("Could not notify '{}' about {}! {}", name, state, err);
+            }
+        }
+    }
+
+    pub fn ready(&self) {
+        self.send(&format!("READY=1\nMAINPID={}", std::process::id()));
+    }
+
+    pub fn reloading(&self) {
+        self.send("RELOADING=1");
+    }
+
+    pub fn reloaded(&self) {
+        self.send("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    fn watchdog(&self) {
+        self.send("WATCHDOG=1");
+    }
+}
+
+// Paths starting with '@' are in the abstract namespace
+fn connect(path: &[u8]) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    match path.split_first() {
+        #[cfg(target_os = "linux")]
+        Some((b'@', name)) => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+            socket.connect_addr(&SocketAddr::from_abstract_name(name)?)?
+        }
+        _ => socket.connect(OsStr::from_bytes(path))?,
+    }
+    Ok(socket)
+}
+
+// Interval of the watchdog pings, only if they are expected from this
+// process
+fn watchdog_interval(
+    pid: u32,
+    watchdog_pid: Option<&str>,
+    watchdog_usec: Option<&str>,
+) -> Option<Duration> {
+    if let Some(watchdog_pid) = watchdog_pid {
+        if watchdog_pid.parse::<u32>().ok()? != pid {
+            return None;
+        }
+    }
+    match watchdog_usec?.parse().ok()? {
+        0 => None,
+        // Pings are sent twice per timeout
+        usec => Some(Duration::from_micros(usec) / 2),
+    }
+}
+
+// Pings the watchdog while the runtime is responsive
+pub async fn watchdog(notifier: Arc<Notifier>) {
+    let interval = watchdog_interval(
+        std::process::id(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+    );
+    let interval = match interval {
+        Some(interval) => interval,
+        None => return,
+    };
+    debug!("Pinging the watchdog every {:?}", interval);
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        notifier.watchdog();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inherit, listen_fds, watchdog_interval, Activated, Notifier};
+    use std::io::ErrorKind;
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    #[test]
+    fn activation_env() {
+        assert_eq!(listen_fds(42, Some("42"), Some("2")), 2);
+        assert_eq!(listen_fds(42, Some("41"), Some("2")), 0);
+        assert_eq!(listen_fds(42, None, Some("2")), 0);
+        assert_eq!(listen_fds(42, Some("42"), None), 0);
+
+        assert_eq!(
+            watchdog_interval(42, None, Some("30000000")),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval(42, Some("42"), Some("1000")),
+            Some(Duration::from_micros(500))
+        );
+        assert_eq!(watchdog_interval(42, Some("41"), Some("1000")), None);
+        assert_eq!(watchdog_interval(42, None, Some("0")), None);
+        assert_eq!(watchdog_interval(42, None, None), None);
+    }
+
+    #[test]
+    fn take_listeners() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut activated = Activated {
+            listeners: vec![(addr, listener)],
+        };
+        assert!(activated.take("127.0.0.1:1".parse().unwrap()).is_none());
+        assert_eq!(activated.take(addr).unwrap().local_addr().unwrap(), addr);
+        assert!(activated.take(addr).is_none());
+    }
+
+    #[test]
+    fn inherit_nonblocking() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let bound = listener.local_addr().unwrap();
+        let (addr, listener) = inherit(listener).unwrap();
+        assert_eq!(addr, bound);
+        // Blocks forever if the socket is still blocking
+        let err = listener.accept().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn notify_socket() {
+        let path = std::env::temp_dir().join(format!("heimdall-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        manager
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let notifier = Notifier::new(path.clone().into_os_string());
+        let mut buffer = [0; 256];
+        let mut receive = || {
+            let len = manager.recv(&mut buffer).unwrap();
+            String::from_utf8(buffer[..len].to_vec()).unwrap()
+        };
+
+        notifier.ready();
+        assert_eq!(
+            receive(),
+            format!("READY=1\nMAINPID={}", std::process::id())
+        );
+        notifier.reloading();
+        assert_eq!(receive(), "RELOADING=1");
+        notifier.reloaded();
+        assert_eq!(receive(), "READY=1");
+        notifier.watchdog();
+        assert_eq!(receive(), "WATCHDOG=1");
+        notifier.stopping();
+        assert_eq!(receive(), "STOPPING=1");
+        std::fs::remove_file(&path).unwrap();
+
+        // Without NOTIFY_SOCKET nothing is sent
+        Notifier::default().ready();
+        assert!(Notifier::new(path.into_os_string()).socket.is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn abstract_notify_socket() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr;
+
+        let name = format!("heimdall-notify-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(&name).unwrap();
+        let manager = UnixDatagram::bind_addr(&addr).unwrap();
+        let notifier = Notifier::new(format!("@{}", name).into());
+        notifier.stopping();
+        let mut buffer = [0; 32];
+        let len = manager.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"STOPPING=1");
+    }
+}
@@ -0,0 +1,326 @@
+use crate::config::{RouteTimeoutsDefinition, TimeoutsDefinition};
+use futures_util::stream::StreamExt;
+use hyper::{Body, Response};
+use log::debug;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Delay;
+
+const DEFAULT_HANDSHAKE_SECS: u64 = 10;
+const DEFAULT_HEADER_READ_SECS: u64 = 10;
+const DEFAULT_IDLE_SECS: u64 = 60;
+const DEFAULT_UPSTREAM_CONNECT_SECS: u64 = 5;
+const DEFAULT_UPSTREAM_RESPONSE_SECS: u64 = 60;
+const DEFAULT_UPGRADE_IDLE_SECS: u64 = 300;
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 30;
+
+// A timeout of 0 seconds disables it
+fn duration(secs: Option<u64>, default: u64) -> Option<Duration> {
+    match secs.unwrap_or(default) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeouts {
+    pub handshake: Option<Duration>,
+    pub header_read: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub upstream_connect: Option<Duration>,
+    pub upstream_response: Option<Duration>,
+    // Upgraded connections without traffic in either direction are closed
+    pub upgrade_idle: Option<Duration>,
+    // Requests in flight on shutdown are cut off afterwards
+    pub shutdown_grace: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn from_config(config: Option<&TimeoutsDefinition>) -> Self {
+        let config = config.cloned().unwrap_or_default();
+        Self {
+            handshake: duration(config.handshake_secs, DEFAULT_HANDSHAKE_SECS),
+            header_read: duration(config.header_read_secs, DEFAULT_HEADER_READ_SECS),
+            idle: duration(config.idle_secs, DEFAULT_IDLE_SECS),
+            upstream_connect: duration(config.upstream_connect_secs, DEFAULT_UPSTREAM_CONNECT_SECS),
+            upstream_response: duration(
+                config.upstream_response_secs,
+                DEFAULT_UPSTREAM_RESPONSE_SECS,
+            ),
+            upgrade_idle: duration(config.upgrade_idle_secs, DEFAULT_UPGRADE_IDLE_SECS),
+            shutdown_grace: duration(config.shutdown_grace_secs, DEFAULT_SHUTDOWN_GRACE_SECS),
+        }
+    }
+
+    // Applies the upstream timeouts of a route
+    pub fn for_route(&self, route: Option<&RouteTimeoutsDefinition>) -> Self {
+        let mut timeouts = *self;
+        if let Some(route) = route {
+            if let Some(secs) = route.upstream_connect_secs {
+                timeouts.upstream_connect = duration(Some(secs), 0);
+            }
+            if let Some(secs) = route.upstream_response_secs {
+                timeouts.upstream_response = duration(Some(secs), 0);
+            }
+            if let Some(secs) = route.upgrade_idle_secs {
+                timeouts.upgrade_idle = duration(Some(secs), 0);
+            }
+        }
+        timeouts
+    }
+}
+
+// Runs the future to completion or returns None once the timeout elapsed
+pub async fn run<F: Future>(timeout: Option<Duration>, future: F) -> Option<F::Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+struct ActivityState {
+    // Requests which are being handled
+    active: usize,
+    // Whether a request was handled on the connection
+    served: bool,
+    // Start of the connection or end of the last request
+    waiting_since: Instant,
+    // First data received for the next request
+    first_byte: Option<Instant>,
+    // Reader waiting for the last request to finish to start the timer
+    waker: Option<Waker>,
+}
+
+// Requests of a connection, used to decide which timeout applies
+pub struct Activity {
+    state: Mutex<ActivityState>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(ActivityState {
+                active: 0,
+                served: false,
+                waiting_since: Instant::now(),
+                first_byte: None,
+                waker: None,
+            }),
+        }
+    }
+}
+
+impl Activity {
+    // Marks a request as active until the returned guard is dropped
+    pub fn start_request(self: &Arc<Self>) -> RequestGuard {
+        self.state.lock().unwrap().active += 1;
+        RequestGuard(self.clone())
+    }
+
+    fn received(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.active == 0 && state.first_byte.is_none() {
+            state.first_byte = Some(Instant::now());
+        }
+    }
+
+    // The headers of a request have to arrive within the header read
+    // timeout, the first request after the handshake and any following
+    // request once it started. Between requests the idle timeout applies.
+    fn deadline(
+        &self,
+        cx: &Context<'_>,
+        header_read: Option<Duration>,
+        idle: Option<Duration>,
+    ) -> Option<Instant> {
+        let mut state = self.state.lock().unwrap();
+        if state.active > 0 {
+            state.waker = Some(cx.waker().clone());
+            return None;
+        }
+        match state.first_byte {
+            Some(first_byte) => header_read.map(|timeout| first_byte + timeout),
+            None if !state.served => header_read.map(|timeout| state.waiting_since + timeout),
+            None => idle.map(|timeout| state.waiting_since + timeout),
+        }
+    }
+}
+
+pub struct RequestGuard(Arc<Activity>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.active -= 1;
+        if state.active == 0 {
+            state.served = true;
+            state.waiting_since = Instant::now();
+            state.first_byte = None;
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+// Keeps the request active until the response body is sent
+pub fn track(response: Response<Body>, guard: RequestGuard) -> Response<Body> {
+    let (parts, body) = response.into_parts();
+    let body = body.map(move |chunk| {
+        let _ = &guard;
+        chunk
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+// Client connection which fails reads once the header read or idle
+// timeout elapsed
+pub struct TimeoutStream<S> {
+    inner: S,
+    activity: Arc<Activity>,
+    header_read: Option<Duration>,
+    idle: Option<Duration>,
+    timer: Option<(Instant, Delay)>,
+}
+
+impl<S> TimeoutStream<S> {
+    pub fn new(inner: S, timeouts: &Timeouts) -> Self {
+        Self {
+            inner,
+            activity: Arc::new(Activity::default()),
+            header_read: timeouts.header_read,
+            idle: timeouts.idle,
+            timer: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn activity(&self) -> &Arc<Activity> {
+        &self.activity
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimeoutStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.activity.deadline(cx, this.header_read, this.idle) {
+            Some(deadline) => {
+                if this.timer.as_ref().map(|(at, _)| *at) != Some(deadline) {
+                    let delay = tokio::time::delay_until(deadline.into());
+                    this.timer = Some((deadline, delay));
+                }
+                if let Some((_, delay)) = &mut this.timer {
+                    if Pin::new(delay).poll(cx).is_ready() {
+                        debug!("Closing connection after read timeout");
+                        return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+                    }
+                }
+            }
+            None => this.timer = None,
+        }
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = &result {
+            if *read > 0 {
+                this.activity.received();
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Activity, TimeoutStream, Timeouts};
+    use crate::config::{RouteTimeoutsDefinition, TimeoutsDefinition};
+    use std::sync::Arc;
+    use std::task::Context;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn route_timeouts() {
+        let timeouts = Timeouts::from_config(Some(&TimeoutsDefinition {
+            idle_secs: Some(0),
+            upstream_response_secs: Some(30),
+            ..Default::default()
+        }));
+        assert_eq!(timeouts.idle, None);
+        assert_eq!(timeouts.handshake, Some(Duration::from_secs(10)));
+        assert_eq!(timeouts.upstream_response, Some(Duration::from_secs(30)));
+        assert_eq!(timeouts.shutdown_grace, Some(Duration::from_secs(30)));
+
+        let route = timeouts.for_route(Some(&RouteTimeoutsDefinition {
+            upstream_connect_secs: Some(1),
+            upstream_response_secs: Some(0),
+            upgrade_idle_secs: Some(30),
+        }));
+        assert_eq!(route.upstream_connect, Some(Duration::from_secs(1)));
+        assert_eq!(route.upstream_response, None);
+        assert_eq!(route.upgrade_idle, Some(Duration::from_secs(30)));
+        assert_eq!(timeouts.for_route(None), timeouts);
+    }
+
+    #[test]
+    fn deadlines() {
+        let waker = futures_util::task::noop_waker();
+        let cx = Context::from_waker(&waker);
+        let header_read = Some(Duration::from_secs(5));
+        let idle = Some(Duration::from_secs(60));
+        let activity = Arc::new(Activity::default());
+        let start = activity.deadline(&cx, header_read, idle).unwrap();
+        let guard = activity.start_request();
+        assert_eq!(activity.deadline(&cx, header_read, idle), None);
+        drop(guard);
+        let waiting = activity.deadline(&cx, header_read, idle).unwrap();
+        assert!(waiting >= start + Duration::from_secs(55));
+        activity.received();
+        let reading = activity.deadline(&cx, header_read, idle).unwrap();
+        assert!(reading < waiting);
+    }
+
+    #[tokio::test]
+    async fn read_timeout() {
+        let (client, server) = tokio::net::UnixStream::pair().unwrap();
+        let timeouts = Timeouts {
+            header_read: Some(Duration::from_millis(50)),
+            ..Timeouts::from_config(None)
+        };
+        let mut server = TimeoutStream::new(server, &timeouts);
+        let mut client = client;
+        client.write_all(b"GET").await.unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(server.read(&mut buf).await.unwrap(), 3);
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+}
@@ -1,37 +1,256 @@
-use crate::config::Config;
-use log::error;
+use crate::acme::{self, Challenges, ACME_TLS_ALPN};
+use crate::config::{AcmeChallenge, CertificateDefinition, Config};
+use crate::util::host_matches_wildcard;
+use arc_swap::ArcSwap;
+use log::{error, info, warn};
 use rustls::internal::pemfile;
-use rustls::ServerConfig;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{ClientHello, ResolvesServerCert, ServerConfig};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fs, io, sync::Arc};
+use x509_parser::prelude::{FromDer, X509Certificate};
 
-pub fn create_config(config: &Config) -> Option<Arc<ServerConfig>> {
-    let certs = match load_certs(&config.cert_file) {
-        Ok(certs) => certs,
-        Err(err) => {
-            error!("Could not load certificate! {}", err);
-            return None;
+// Selects the certificate by the SNI server name sent by the client
+// Clients without SNI or with an unknown name get the default certificate
+#[derive(Default)]
+pub struct CertResolver {
+    certs: HashMap<String, CertifiedKey>,
+    // Wildcard certificates stored by suffix ('.example.com')
+    wildcards: Vec<(String, CertifiedKey)>,
+    default: Option<CertifiedKey>,
+}
+
+impl CertResolver {
+    // Certificates which fail to load are taken over from the previous
+    // resolver, so a broken renewal does not take a domain offline
+    pub fn from_config(config: &Config, previous: Option<&CertResolver>) -> Self {
+        let mut resolver = Self::default();
+        for entry in &certificate_entries(config) {
+            match load_certified_key(&entry.cert_file, &entry.pkey_file) {
+                Ok(key) => resolver.add(entry, key),
+                Err(err) => {
+                    for domain in &entry.domains {
+                        error!("Could not load certificate for '{}'! {}", domain, err);
+                        let domain = domain.trim_end_matches('.').to_lowercase();
+                        if let Some(key) = previous.and_then(|p| p.lookup(&domain)) {
+                            warn!("Keeping previous certificate for '{}'", domain);
+                            resolver.insert(domain, key.clone());
+                        }
+                    }
+                }
+            }
         }
-    };
-    let pkey = match load_private_key(&config.pkey_file) {
-        Ok(pkey) => pkey,
-        Err(err) => {
-            error!("Could not load private key! {}", err);
-            return None;
+
+        if let Some(domain) = &config.default_certificate {
+            match resolver.lookup(&domain.to_lowercase()) {
+                Some(key) => resolver.default = Some(key.clone()),
+                None => error!("Default certificate for '{}' is not available!", domain),
+            }
         }
-    };
+        if resolver.default.is_none() {
+            if let (Some(cert_file), Some(pkey_file)) = (&config.cert_file, &config.pkey_file) {
+                match load_certified_key(cert_file, pkey_file) {
+                    Ok(key) => resolver.default = Some(key),
+                    Err(err) => {
+                        error!("Could not load default certificate! {}", err);
+                        resolver.default = previous.and_then(|p| p.default.clone());
+                    }
+                }
+            }
+        }
+        if resolver.default.is_none() {
+            warn!("No default certificate, clients without SNI will be rejected!");
+        }
+        resolver
+    }
+
+    fn add(&mut self, entry: &CertificateDefinition, key: CertifiedKey) {
+        for domain in &entry.domains {
+            let domain = domain.trim_end_matches('.').to_lowercase();
+            if domain.starts_with("*.") {
+                self.insert(domain, key.clone());
+                continue;
+            }
+            match webpki::DNSNameRef::try_from_ascii_str(&domain) {
+                Ok(name) => {
+                    if let Err(err) = key.cross_check_end_entity_cert(Some(name)) {
+                        error!("Certificate for '{}' is not usable! {}", domain, err);
+                        continue;
+                    }
+                }
+                Err(_) => {
+                    error!("Invalid domain name '{}' for certificate!", domain);
+                    continue;
+                }
+            }
+            self.insert(domain, key.clone());
+        }
+    }
+
+    fn insert(&mut self, domain: String, key: CertifiedKey) {
+        if domain.starts_with("*.") {
+            self.wildcards.push((domain[1..].to_owned(), key));
+            self.wildcards
+                .sort_by_key(|(s, _)| std::cmp::Reverse(s.len()));
+        } else {
+            self.certs.insert(domain, key);
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<&CertifiedKey> {
+        self.certs.get(name).or_else(|| {
+            self.wildcards
+                .iter()
+                .find(|(suffix, _)| host_matches_wildcard(suffix, name))
+                .map(|(_, key)| key)
+        })
+    }
+
+    // Names and expiry times of the loaded certificates, the default
+    // certificate is listed as 'default'
+    pub fn expiries(&self) -> Vec<(String, Option<SystemTime>)> {
+        let expiry = |key: &CertifiedKey| certificate_expiry(&key.cert.first()?.0);
+        let mut expiries: Vec<_> = self
+            .certs
+            .iter()
+            .map(|(domain, key)| (domain.clone(), expiry(key)))
+            .chain(
+                self.wildcards
+                    .iter()
+                    .map(|(suffix, key)| (format!("*{}", suffix), expiry(key))),
+            )
+            .collect();
+        expiries.sort();
+        if let Some(key) = &self.default {
+            expiries.push(("default".to_owned(), expiry(key)));
+        }
+        expiries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.certs.is_empty() && self.wildcards.is_empty() && self.default.is_none()
+    }
+
+    fn resolve_name(&self, name: Option<&str>) -> Option<CertifiedKey> {
+        let key = name.and_then(|name| self.lookup(&name.to_lowercase()));
+        key.or(self.default.as_ref()).cloned()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        self.resolve_name(client_hello.server_name().map(Into::into))
+    }
+}
+
+// Holds the current certificate resolver which can be swapped at runtime
+// New handshakes use the latest certificates, established connections
+// are not affected
+// TLS-ALPN-01 validation handshakes are answered with the pending
+// challenge certificate
+pub struct ReloadableResolver {
+    current: ArcSwap<CertResolver>,
+    challenges: Arc<Challenges>,
+}
+
+impl ReloadableResolver {
+    // Without ACME at least one certificate is required, with ACME the
+    // certificates may not have been obtained yet
+    pub fn from_config(config: &Config, challenges: Arc<Challenges>) -> Option<Self> {
+        let resolver = CertResolver::from_config(config, None);
+        if resolver.is_empty() {
+            if config.acme.is_none() {
+                error!("Could not load any certificate!");
+                return None;
+            }
+            warn!("No certificate available yet, waiting for ACME");
+        }
+        Some(Self {
+            current: ArcSwap::from_pointee(resolver),
+            challenges,
+        })
+    }
+
+    pub fn reload(&self, config: &Config) {
+        let previous = self.current.load();
+        let resolver = CertResolver::from_config(config, Some(&previous));
+        if resolver.is_empty() {
+            error!("Could not load any certificate, keeping previous ones!");
+            return;
+        }
+        self.current.store(Arc::new(resolver));
+        info!("Reloaded certificates");
+    }
+
+    pub fn expiries(&self) -> Vec<(String, Option<SystemTime>)> {
+        self.current.load().expiries()
+    }
+}
+
+impl ResolvesServerCert for ReloadableResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let alpn = client_hello.alpn().unwrap_or_default();
+        if alpn.len() == 1 && alpn[0] == ACME_TLS_ALPN {
+            let name: &str = client_hello.server_name()?.into();
+            return self.challenges.tls_alpn_cert(&name.to_lowercase());
+        }
+        self.current.load().resolve(client_hello)
+    }
+}
+
+pub fn create_config(config: &Config, resolver: Arc<ReloadableResolver>) -> Arc<ServerConfig> {
     let mut cfg = rustls::ServerConfig::new(rustls::NoClientAuth::new());
-    if let Err(err) = cfg.set_single_cert(certs, pkey) {
-        error!("Could not setup TLS! {}", err);
-        return None;
+    cfg.cert_resolver = resolver;
+    if let Some(acme) = &config.acme {
+        if acme.challenge == AcmeChallenge::TlsAlpn01 {
+            cfg.set_protocols(&[ACME_TLS_ALPN.to_vec()]);
+        }
     }
-    Some(Arc::new(cfg))
+    Arc::new(cfg)
+}
+
+// Configured certificates and those obtained through ACME
+fn certificate_entries(config: &Config) -> Vec<CertificateDefinition> {
+    let mut entries = config.certificates.clone();
+    entries.extend(acme::certificate_entries(config));
+    entries
+}
+
+// Modification times of all configured certificate and key files
+pub fn modification_times(config: &Config) -> HashMap<String, Option<SystemTime>> {
+    let entries = certificate_entries(config);
+    let mut files: Vec<&String> = entries
+        .iter()
+        .flat_map(|entry| vec![&entry.cert_file, &entry.pkey_file])
+        .collect();
+    files.extend(config.cert_file.iter().chain(config.pkey_file.iter()));
+    files
+        .into_iter()
+        .map(|file| {
+            let modified = fs::metadata(file).and_then(|m| m.modified()).ok();
+            (file.clone(), modified)
+        })
+        .collect()
 }
 
 fn error(err: String) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, err)
+    io::Error::other(err)
+}
+
+fn load_certified_key(cert_file: &str, pkey_file: &str) -> io::Result<CertifiedKey> {
+    let certs = load_certs(cert_file)?;
+    if certs.is_empty() {
+        return Err(error(format!("no certificate found in {}", cert_file)));
+    }
+    let pkey = load_private_key(pkey_file)?;
+    let key = sign::any_supported_type(&pkey)
+        .map_err(|_| error(format!("unsupported private key in {}", pkey_file)))?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
 }
 
-fn load_certs(filename: &str) -> io::Result<Vec<rustls::Certificate>> {
+pub fn load_certs(filename: &str) -> io::Result<Vec<rustls::Certificate>> {
     let certfile = fs::File::open(filename)
         .map_err(|e| error(format!("failed to open {}: {}", filename, e)))?;
     let mut reader = io::BufReader::new(certfile);
@@ -51,3 +270,210 @@ fn load_private_key(filename: &str) -> io::Result<rustls::PrivateKey> {
     }
     Ok(keys[0].clone())
 }
+
+// Reads the 'notAfter' time of a DER encoded X.509 certificate
+pub fn certificate_expiry(cert: &[u8]) -> Option<SystemTime> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let secs = u64::try_from(cert.validity().not_after.timestamp()).ok()?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// Writes a self signed certificate for the names, returns the paths of
+// the certificate and key files
+#[cfg(test)]
+pub fn write_test_certificate(file: &str, names: &[&str]) -> (String, String) {
+    let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+    let cert = rcgen::generate_simple_self_signed(names).unwrap();
+    let dir = std::env::temp_dir().join(format!("heimdall-certs-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cert_file = dir.join(format!("{}.pem", file));
+    let pkey_file = dir.join(format!("{}.key", file));
+    fs::write(&cert_file, cert.serialize_pem().unwrap()).unwrap();
+    fs::write(&pkey_file, cert.serialize_private_key_pem()).unwrap();
+    (
+        cert_file.to_str().unwrap().to_owned(),
+        pkey_file.to_str().unwrap().to_owned(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        certificate_expiry, load_certs, write_test_certificate, CertResolver, ReloadableResolver,
+    };
+    use crate::acme::Challenges;
+    use crate::config::{CertificateDefinition, Config};
+    use rcgen::{date_time_ymd, Certificate, CertificateParams};
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn entry(file: &str, domain: &str) -> CertificateDefinition {
+        let (cert_file, pkey_file) = write_test_certificate(file, &[domain]);
+        CertificateDefinition {
+            domains: vec![domain.to_owned()],
+            cert_file,
+            pkey_file,
+        }
+    }
+
+    fn der(file: &str) -> Vec<u8> {
+        load_certs(file).unwrap().remove(0).0
+    }
+
+    #[test]
+    fn sni() {
+        let exact = entry("sni-exact", "a.test");
+        let wildcard = entry("sni-wildcard", "*.b.test");
+        let longer = entry("sni-longer", "*.x.b.test");
+        let (cert_file, pkey_file) = write_test_certificate("sni-default", &["default.test"]);
+        let mut config = Config {
+            certificates: vec![exact.clone(), wildcard.clone(), longer.clone()],
+            ..Default::default()
+        };
+        let resolver = CertResolver::from_config(&config, None);
+        let resolved = |resolver: &CertResolver, name: Option<&str>| {
+            resolver.resolve_name(name).map(|key| key.cert[0].0.clone())
+        };
+
+        assert_eq!(
+            resolved(&resolver, Some("a.test")),
+            Some(der(&exact.cert_file))
+        );
+        assert_eq!(
+            resolved(&resolver, Some("A.Test")),
+            Some(der(&exact.cert_file))
+        );
+        assert_eq!(
+            resolved(&resolver, Some("www.b.test")),
+            Some(der(&wildcard.cert_file))
+        );
+        // The longest wildcard suffix wins
+        assert_eq!(
+            resolved(&resolver, Some("www.x.b.test")),
+            Some(der(&longer.cert_file))
+        );
+        assert_eq!(
+            resolved(&resolver, Some("x.b.test")),
+            Some(der(&wildcard.cert_file))
+        );
+        // Without a default certificate unknown names are rejected
+        assert_eq!(resolved(&resolver, Some("b.test")), None);
+        assert_eq!(resolved(&resolver, Some("unknown.test")), None);
+        assert_eq!(resolved(&resolver, None), None);
+
+        config.cert_file = Some(cert_file.clone());
+        config.pkey_file = Some(pkey_file);
+        let resolver = CertResolver::from_config(&config, None);
+        assert_eq!(
+            resolved(&resolver, Some("a.test")),
+            Some(der(&exact.cert_file))
+        );
+        assert_eq!(
+            resolved(&resolver, Some("unknown.test")),
+            Some(der(&cert_file))
+        );
+        assert_eq!(resolved(&resolver, None), Some(der(&cert_file)));
+
+        // 'default_certificate' selects one of the named certificates
+        config.default_certificate = Some("A.test".to_owned());
+        let resolver = CertResolver::from_config(&config, None);
+        assert_eq!(resolved(&resolver, None), Some(der(&exact.cert_file)));
+    }
+
+    #[test]
+    fn reload() {
+        let exact = entry("reload-exact", "a.test");
+        let (cert_file, pkey_file) = write_test_certificate("reload-default", &["default.test"]);
+        let config = Config {
+            certificates: vec![exact.clone()],
+            cert_file: Some(cert_file.clone()),
+            pkey_file: Some(pkey_file.clone()),
+            ..Default::default()
+        };
+        let resolver =
+            ReloadableResolver::from_config(&config, Arc::new(Challenges::default())).unwrap();
+        let resolved = |name: Option<&str>| {
+            let current = resolver.current.load();
+            current.resolve_name(name).map(|key| key.cert[0].0.clone())
+        };
+        let (exact_der, default_der) = (der(&exact.cert_file), der(&cert_file));
+
+        // A broken certificate and a missing default keep the previous ones
+        std::fs::write(&exact.cert_file, "broken").unwrap();
+        std::fs::remove_file(&cert_file).unwrap();
+        resolver.reload(&config);
+        assert_eq!(resolved(Some("a.test")), Some(exact_der.clone()));
+        assert_eq!(resolved(None), Some(default_der.clone()));
+
+        // Nothing loadable at all
+        std::fs::remove_file(&exact.cert_file).unwrap();
+        std::fs::remove_file(&pkey_file).unwrap();
+        resolver.reload(&config);
+        assert_eq!(resolved(Some("a.test")), Some(exact_der.clone()));
+        assert_eq!(resolved(None), Some(default_der));
+
+        // Fixed files are picked up again
+        let renewed = entry("reload-exact", "a.test");
+        resolver.reload(&config);
+        assert_ne!(der(&renewed.cert_file), exact_der);
+        assert_eq!(resolved(Some("a.test")), Some(der(&renewed.cert_file)));
+    }
+
+    #[test]
+    fn expiry() {
+        let mut params = CertificateParams::new(vec!["foo.bar".to_owned()]);
+        params.not_after = date_time_ymd(2030, 6, 1);
+        let cert = Certificate::from_params(params).unwrap();
+        assert_eq!(
+            certificate_expiry(&cert.serialize_der().unwrap()),
+            Some(UNIX_EPOCH + Duration::from_secs(1_906_502_400))
+        );
+
+        let mut params = CertificateParams::new(vec!["foo.bar".to_owned()]);
+        params.not_after = date_time_ymd(2060, 1, 1);
+        let cert = Certificate::from_params(params).unwrap();
+        assert_eq!(
+            certificate_expiry(&cert.serialize_der().unwrap()),
+            Some(UNIX_EPOCH + Duration::from_secs(2_840_140_800))
+        );
+    }
+
+    #[test]
+    fn malformed_certificate() {
+        let cert = rcgen::generate_simple_self_signed(vec!["foo.bar".to_owned()])
+            .unwrap()
+            .serialize_der()
+            .unwrap();
+        assert!(certificate_expiry(&cert).is_some());
+        for len in 0..cert.len() {
+            assert_eq!(
+                certificate_expiry(&cert[..len]),
+                None,
+                "truncated to {}",
+                len
+            );
+        }
+
+        assert_eq!(certificate_expiry(b""), None);
+        assert_eq!(certificate_expiry(b"invalid"), None);
+        // Wrong outer tag, length beyond the data and an oversized length
+        let mut wrong_tag = cert.clone();
+        wrong_tag[0] = 0x31;
+        assert_eq!(certificate_expiry(&wrong_tag), None);
+        assert_eq!(certificate_expiry(&[0x30, 0x82, 0xff, 0xff, 0x30]), None);
+        assert_eq!(
+            certificate_expiry(&[0x30, 0x88, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            None
+        );
+        // A validity with month 99
+        let not_after = cert
+            .windows(2)
+            .rposition(|window| window == [0x18, 0x0f] || window == [0x17, 0x0d])
+            .unwrap();
+        let month = not_after + if cert[not_after] == 0x18 { 6 } else { 4 };
+        let mut invalid_time = cert.clone();
+        invalid_time[month] = b'9';
+        invalid_time[month + 1] = b'9';
+        assert_eq!(certificate_expiry(&invalid_time), None);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::access_log::UpstreamInfo;
+use crate::forwarded::Peer;
+use crate::headers::Variables;
+use crate::proxy::{self, error_response, prepare, unavailable, ProxyError};
+use crate::router::Forward;
+use crate::timeout::{self, RequestGuard};
+use crate::upstream;
+use futures_util::future::{self, Either};
+use hyper::header::{HeaderValue, CONNECTION, UPGRADE};
+use hyper::{Body, Request, Response, StatusCode, Version};
+use log::{debug, error, warn};
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+// Whether the client asks to switch protocols, e.g. for a WebSocket
+pub fn is_upgrade(request: &Request<Body>) -> bool {
+    request.version() == Version::HTTP_11
+        && request.headers().contains_key(UPGRADE)
+        && request
+            .headers()
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+// Forwards an upgrade request to a backend of the route. If the backend
+// switches protocols both connections are joined until either side closes
+// or no data was sent for the idle timeout of the route. The request stays
+// active until then so the client connection timeouts do not apply.
+pub async fn forward(
+    request: Request<Body>,
+    peer: &Peer,
+    forward: &Forward,
+    guard: RequestGuard,
+) -> Response<Body> {
+    let route = &forward.route;
+    let upstream = match route.balancer.select(&request, peer.client_ip, &[]) {
+        Some(upstream) => upstream,
+        None => return timeout::track(unavailable(&route.balancer), guard),
+    };
+    let variables = Variables::new(&request, peer, route.balancer.route());
+    let protocol = request.headers()[UPGRADE].clone();
+    let (parts, body) = request.into_parts();
+    let client = body.on_upgrade();
+    let mut request = Request::from_parts(parts, Body::empty());
+    request = prepare(
+        request,
+        peer,
+        forward.uri(&upstream),
+        route.request_headers.as_ref(),
+        &variables,
+    )
+    .await;
+    let headers = request.headers_mut();
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, protocol);
+
+    let started = Instant::now();
+    let result = match timeout::run(route.response_timeout, upstream.send(request)).await {
+        Some(result) => result.map_err(ProxyError::from),
+        None => Err(ProxyError::Timeout {
+            timeout: route.response_timeout.unwrap(),
+        }),
+    };
+    proxy::record(
+        &upstream,
+        upstream.ticket,
+        result.as_ref().map(|(response, _)| response.status()),
+    );
+    let info = UpstreamInfo {
+        addr: upstream.addr(),
+        latency: started.elapsed(),
+    };
+    let (mut response, in_flight) = match result {
+        Ok(result) => result,
+        Err(err) => {
+            error!(
+                "Upgrade request for route {} to {} failed! {}",
+                route.balancer.route(),
+                upstream.addr(),
+                err
+            );
+            let mut response = error_response(&err);
+            response.extensions_mut().insert(info);
+            return timeout::track(response, guard);
+        }
+    };
+    response.extensions_mut().insert(info);
+    if let Some(rules) = &route.response_headers {
+        rules.apply(response.headers_mut(), &variables);
+    }
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        // The backend declined, answer like a normal request
+        return timeout::track(upstream::hold(response, in_flight), guard);
+    }
+
+    let (parts, body) = response.into_parts();
+    let addr = upstream.addr();
+    let idle = route.upgrade_idle;
+    tokio::spawn(async move {
+        match future::try_join(client, body.on_upgrade()).await {
+            Ok((client, backend)) => match tunnel(client, backend, idle).await {
+                Ok((sent, received)) => debug!(
+                    "Upgraded connection to {} closed, {} bytes sent, {} bytes received",
+                    addr, sent, received
+                ),
+                Err(err) => debug!("Upgraded connection to {} failed! {}", addr, err),
+            },
+            Err(err) => warn!("Could not upgrade connection to {}! {}", addr, err),
+        }
+        drop(in_flight);
+        drop(guard);
+    });
+    Response::from_parts(parts, Body::empty())
+}
+
+// Copies data in both directions until both sides are closed or the idle
+// timeout elapsed, returns the bytes sent to and received from the backend
+async fn tunnel<C, B>(client: C, backend: B, idle: Option<Duration>) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let last_activity = Mutex::new(Instant::now());
+    let (client_read, client_write) = tokio::io::split(client);
+    let (backend_read, backend_write) = tokio::io::split(backend);
+    let copies = future::try_join(
+        copy(client_read, backend_write, &last_activity),
+        copy(backend_read, client_write, &last_activity),
+    );
+    let watchdog = watch_idle(idle, &last_activity);
+    futures_util::pin_mut!(copies, watchdog);
+    match future::select(copies, watchdog).await {
+        Either::Left((result, _)) => result,
+        Either::Right(((), _)) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("No data within {:?}", idle.unwrap()),
+        )),
+    }
+}
+
+async fn copy<R, W>(mut reader: R, mut writer: W, last_activity: &Mutex<Instant>) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut total = 0;
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            // Forward the half-close, the other direction may go on
+            writer.shutdown().await?;
+            return Ok(total);
+        }
+        writer.write_all(&buffer[..read]).await?;
+        *last_activity.lock().unwrap() = Instant::now();
+        total += read as u64;
+    }
+}
+
+// Completes once no data was copied for the idle timeout
+async fn watch_idle(idle: Option<Duration>, last_activity: &Mutex<Instant>) {
+    let idle = match idle {
+        Some(idle) => idle,
+        None => return future::pending().await,
+    };
+    loop {
+        let deadline = *last_activity.lock().unwrap() + idle;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::delay_until(deadline.into()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_upgrade, tunnel};
+    use hyper::{Body, Request, Version};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn detect_upgrade() {
+        let request = |connection: &str| {
+            Request::get("/")
+                .header("Connection", connection)
+                .header("Upgrade", "websocket")
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert!(is_upgrade(&request("Upgrade")));
+        assert!(is_upgrade(&request("keep-alive, upgrade")));
+        assert!(!is_upgrade(&request("keep-alive")));
+
+        let mut http10 = request("upgrade");
+        *http10.version_mut() = Version::HTTP_10;
+        assert!(!is_upgrade(&http10));
+        let plain = Request::get("/")
+            .header("Connection", "upgrade")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!is_upgrade(&plain));
+    }
+
+    #[tokio::test]
+    async fn copy_both_directions() {
+        let (mut client, proxy_client) = UnixStream::pair().unwrap();
+        let (mut backend, proxy_backend) = UnixStream::pair().unwrap();
+        let tunnel = tokio::spawn(tunnel(proxy_client, proxy_backend, None));
+
+        let mut buffer = [0; 5];
+        client.write_all(b"hello").await.unwrap();
+        backend.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello");
+        backend.write_all(b"world!").await.unwrap();
+        drop(backend);
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"world!");
+        drop(client);
+        assert_eq!(tunnel.await.unwrap().unwrap(), (5, 6));
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let (_client, proxy_client) = UnixStream::pair().unwrap();
+        let (_backend, proxy_backend) = UnixStream::pair().unwrap();
+        let idle = Some(Duration::from_millis(50));
+        let err = tunnel(proxy_client, proxy_backend, idle).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::breaker::CircuitBreaker;
+use crate::config::{CircuitBreakerDefinition, PoolDefinition};
+use futures_util::future::TryFutureExt;
+use futures_util::stream::StreamExt;
+use hyper::client::HttpConnector;
+use hyper::header::USER_AGENT;
+use hyper::service::Service;
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+use log::debug;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 90;
+
+// Counters to determine how often pooled connections are reused
+#[derive(Default)]
+pub struct PoolStats {
+    requests: AtomicU64,
+    connections: AtomicU64,
+    active: AtomicUsize,
+}
+
+impl PoolStats {
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    // Requests which are waiting for or streaming their response
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    // Requests which were sent over an already established connection
+    pub fn reused(&self) -> u64 {
+        self.requests().saturating_sub(self.connections())
+    }
+
+    // Continues the counts of the previous pool so the metrics keep
+    // increasing across reloads
+    pub fn restore(&self, previous: &PoolStats) {
+        self.requests
+            .fetch_add(previous.requests(), Ordering::Relaxed);
+        self.connections
+            .fetch_add(previous.connections(), Ordering::Relaxed);
+    }
+}
+
+// Http connector which counts the newly established connections
+#[derive(Clone)]
+struct CountingConnector {
+    http: HttpConnector,
+    addr: SocketAddr,
+    stats: Arc<PoolStats>,
+}
+
+impl Service<Uri> for CountingConnector {
+    type Response = TcpStream;
+    type Error = <HttpConnector as Service<Uri>>::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let stats = self.stats.clone();
+        let addr = self.addr;
+        Box::pin(self.http.call(uri).map_ok(move |stream| {
+            let total = stats.connections.fetch_add(1, Ordering::Relaxed) + 1;
+            debug!("Opened connection to upstream {} ({} total)", addr, total);
+            stream
+        }))
+    }
+}
+
+// Marks a request as active until its response body is done or its
+// upgraded connection is closed
+pub struct InFlight {
+    stats: Arc<PoolStats>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl InFlight {
+    fn new(stats: Arc<PoolStats>, permit: Option<OwnedSemaphorePermit>) -> Self {
+        stats.active.fetch_add(1, Ordering::Relaxed);
+        Self {
+            stats,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// A backend with its own long-lived connection pool
+pub struct Upstream {
+    addr: SocketAddr,
+    connect_timeout: Option<Duration>,
+    client: Client<CountingConnector>,
+    // Limits the requests in flight, hyper opens as many connections as
+    // there are concurrent requests
+    limit: Option<Arc<Semaphore>>,
+    stats: Arc<PoolStats>,
+    breaker: Option<CircuitBreaker>,
+    // Disabled through the admin API, requests in flight are finished
+    disabled: AtomicBool,
+}
+
+impl Upstream {
+    pub fn new(
+        addr: SocketAddr,
+        pool: &PoolDefinition,
+        breaker: Option<&CircuitBreakerDefinition>,
+        connect_timeout: Option<Duration>,
+    ) -> Self {
+        let stats = Arc::new(PoolStats::default());
+        let mut http = HttpConnector::new();
+        http.set_nodelay(true);
+        http.set_connect_timeout(connect_timeout);
+        let connector = CountingConnector {
+            http,
+            addr,
+            stats: stats.clone(),
+        };
+        let idle_timeout = pool.idle_timeout_secs.unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS);
+        let mut builder = Client::builder();
+        builder.pool_idle_timeout(Duration::from_secs(idle_timeout));
+        if let Some(max_idle) = pool.max_idle_per_host {
+            builder.pool_max_idle_per_host(max_idle);
+        }
+        Self {
+            addr,
+            connect_timeout,
+            client: builder.build(connector),
+            limit: pool
+                .max_in_flight
+                .map(|max| Arc::new(Semaphore::new(max.max(1)))),
+            stats,
+            breaker: breaker.map(|config| CircuitBreaker::new(addr, config)),
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    pub fn stats(&self) -> &PoolStats {
+        &self.stats
+    }
+
+    pub fn breaker(&self) -> Option<&CircuitBreaker> {
+        self.breaker.as_ref()
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_disabled(&self, disabled: bool) {
+        self.disabled.store(disabled, Ordering::Relaxed);
+    }
+
+    // Sends the request through the pool, the request counts as active and
+    // holds its connection slot until the response body is done
+    pub async fn request(&self, request: Request<Body>) -> hyper::Result<Response<Body>> {
+        let (response, in_flight) = self.send(request).await?;
+        Ok(hold(response, in_flight))
+    }
+
+    // Sends the request through the pool, the returned guard keeps it active
+    pub async fn send(&self, request: Request<Body>) -> hyper::Result<(Response<Body>, InFlight)> {
+        let permit = match &self.limit {
+            Some(limit) => Some(limit.clone().acquire_owned().await),
+            None => None,
+        };
+        let in_flight = InFlight::new(self.stats.clone(), permit);
+        self.stats.requests.fetch_add(1, Ordering::Relaxed);
+        let response = self.client.request(request).await?;
+        Ok((response, in_flight))
+    }
+
+    // Requests the path for health checking, not counted in the stats
+    pub async fn probe(&self, path: &str) -> hyper::Result<StatusCode> {
+        let uri = format!("http://{}{}", self.addr, path);
+        let request = Request::get(uri)
+            .header(USER_AGENT, "heimdall-health-check")
+            .body(Body::empty())
+            .unwrap();
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        hyper::body::to_bytes(response.into_body()).await?;
+        Ok(status)
+    }
+}
+
+// Keeps the request active until the response body is done
+pub fn hold(response: Response<Body>, in_flight: InFlight) -> Response<Body> {
+    let (parts, body) = response.into_parts();
+    let body = body.map(move |chunk| {
+        let _ = &in_flight;
+        chunk
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+impl fmt::Debug for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upstream({})", self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PoolStats, Upstream};
+    use crate::config::PoolDefinition;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn connection_reuse() {
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Body::from("ok")))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let upstream = Upstream::new(addr, &PoolDefinition::default(), None, None);
+        for _ in 0..3 {
+            let uri = format!("http://{}/", addr);
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let response = upstream.request(request).await.unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+        assert_eq!(upstream.stats().requests(), 3);
+        assert_eq!(upstream.stats().connections(), 1);
+        assert_eq!(upstream.stats().reused(), 2);
+    }
+
+    #[test]
+    fn restore_stats() {
+        let previous = PoolStats::default();
+        previous.requests.store(5, Ordering::Relaxed);
+        previous.connections.store(2, Ordering::Relaxed);
+        previous.active.store(1, Ordering::Relaxed);
+        let stats = PoolStats::default();
+        stats.requests.store(1, Ordering::Relaxed);
+        stats.connections.store(1, Ordering::Relaxed);
+        stats.restore(&previous);
+        assert_eq!(stats.requests(), 6);
+        assert_eq!(stats.connections(), 3);
+        assert_eq!(stats.reused(), 3);
+        assert_eq!(stats.active(), 0);
+    }
+}
@@ -1,3 +1,4 @@
+use hyper::header::HOST;
 use hyper::http::uri::{Authority, Scheme};
 use hyper::http::Uri;
 use hyper::{Body, Method, Request};
@@ -5,6 +6,7 @@ use std::ffi::OsStr;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::str::FromStr;
 
 // Upgrade the uri scheme from HTTP to HTTPS
 pub fn rewrite_uri_scheme(uri: Uri) -> Uri {
@@ -21,6 +23,26 @@ pub fn rewrite_uri_scheme(uri: Uri) -> Uri {
         .unwrap()
 }
 
+// Extracts the requested host name without port, lowercased
+// The uri authority (HTTP/2 ':authority' or absolute-form) takes precedence
+// over the 'Host' header
+pub fn request_host(request: &Request<Body>) -> Option<String> {
+    let host = match request.uri().host() {
+        Some(host) => host.to_owned(),
+        None => {
+            let header = request.headers().get(HOST)?.to_str().ok()?;
+            Authority::from_str(header).ok()?.host().to_owned()
+        }
+    };
+    Some(host.trim_end_matches('.').to_lowercase())
+}
+
+// Checks if the host matches a wildcard suffix, e.g. '.example.com'
+// matches 'www.example.com' and 'a.b.example.com' but not 'example.com'
+pub fn host_matches_wildcard(suffix: &str, host: &str) -> bool {
+    host.len() > suffix.len() && host.ends_with(suffix)
+}
+
 // This checks if the incoming request is done by an ACME bot
 // Checks if the path has 4 elements based on the example challenge request from documentation
 // First '.well-known', second 'acme-challenge', the third being the token
@@ -64,7 +86,7 @@ pub fn get_token(webroot: &str, token: &str) -> Option<Vec<u8>> {
 
 #[cfg(test)]
 mod tests {
-    use super::{is_acme_challenge, rewrite_uri_scheme};
+    use super::{host_matches_wildcard, is_acme_challenge, request_host, rewrite_uri_scheme};
     use hyper::http::Uri;
     use hyper::{Body, Method, Request};
     use std::str::FromStr;
@@ -105,6 +127,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn check_request_host() {
+        assert_eq!(request_host(&build_req("/", Method::GET)), None);
+        assert_eq!(
+            request_host(&build_req("https://Foo.Bar:8443/asdf", Method::GET)),
+            Some("foo.bar".to_owned())
+        );
+        let mut req = build_req("/asdf", Method::GET);
+        req.headers_mut()
+            .insert("host", "www.foo.bar.:80".parse().unwrap());
+        assert_eq!(request_host(&req), Some("www.foo.bar".to_owned()));
+        let mut req = build_req("/asdf", Method::GET);
+        req.headers_mut()
+            .insert("host", "[::1]:443".parse().unwrap());
+        assert_eq!(request_host(&req), Some("[::1]".to_owned()));
+
+        assert!(host_matches_wildcard(".foo.bar", "www.foo.bar"));
+        assert!(host_matches_wildcard(".foo.bar", "a.b.foo.bar"));
+        assert!(!host_matches_wildcard(".foo.bar", "foo.bar"));
+        assert!(!host_matches_wildcard(".foo.bar", "www.foo.baz"));
+    }
+
     #[test]
     fn check_rewrite_uri() {
         assert_eq!(